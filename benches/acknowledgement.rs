@@ -0,0 +1,57 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use lapin::unstable::Acknowledgements;
+
+/// The previous implementation, scanning all the pending confirms, kept around for comparison
+mod legacy {
+  use std::collections::{HashMap, HashSet};
+
+  use lapin::unstable::{Wait, WaitHandle};
+
+  #[derive(Default)]
+  pub struct Acknowledgements {
+    pending: HashMap<u64, WaitHandle<()>>,
+  }
+
+  impl Acknowledgements {
+    pub fn register_pending(&mut self, delivery_tag: u64) {
+      let (_, wait_handle) = Wait::new();
+      self.pending.insert(delivery_tag, wait_handle);
+    }
+
+    pub fn ack_all_before(&mut self, delivery_tag: u64) {
+      let tags: HashSet<u64> = self.pending.iter().map(|tup| tup.0).filter(|tag| **tag <= delivery_tag).cloned().collect();
+      for tag in tags {
+        if let Some(wait_handle) = self.pending.remove(&tag) {
+          wait_handle.finish(());
+        }
+      }
+    }
+  }
+}
+
+const IN_FLIGHT: u64 = 50_000;
+const ACK_EVERY: u64 = 100;
+
+fn multiple_acks(c: &mut Criterion) {
+  c.bench_function("legacy multiple acks, 50k in flight", |b| b.iter(|| {
+    let mut acknowledgements = legacy::Acknowledgements::default();
+    for tag in 1..=IN_FLIGHT {
+      acknowledgements.register_pending(tag);
+    }
+    for tag in (ACK_EVERY..=IN_FLIGHT).step_by(ACK_EVERY as usize) {
+      acknowledgements.ack_all_before(tag);
+    }
+  }));
+  c.bench_function("multiple acks, 50k in flight", |b| b.iter(|| {
+    let acknowledgements = Acknowledgements::default();
+    for tag in 1..=IN_FLIGHT {
+      acknowledgements.register_pending(tag);
+    }
+    for tag in (ACK_EVERY..=IN_FLIGHT).step_by(ACK_EVERY as usize) {
+      acknowledgements.ack_all_before(tag).unwrap();
+    }
+  }));
+}
+
+criterion_group!(benches, multiple_acks);
+criterion_main!(benches);
@@ -0,0 +1,87 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use lapin::unstable::Wait;
+
+use std::thread;
+
+/// The previous implementation, based on a sync_channel, kept around for comparison
+#[allow(dead_code)]
+mod legacy {
+  use parking_lot::Mutex;
+
+  use std::sync::{
+    Arc,
+    mpsc::{SyncSender, Receiver, sync_channel},
+  };
+
+  use lapin::{Error, confirmation::NotifyReady};
+
+  pub struct Wait<T> {
+    recv: Receiver<Result<T, Error>>,
+    send: SyncSender<Result<T, Error>>,
+    task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+  }
+
+  pub struct WaitHandle<T> {
+    send: SyncSender<Result<T, Error>>,
+    task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+  }
+
+  impl<T> Wait<T> {
+    pub fn new() -> (Self, WaitHandle<T>) {
+      let (send, recv) = sync_channel(1);
+      let wait         = Self { recv, send, task: Arc::new(Mutex::new(None)) };
+      let wait_handle  = WaitHandle { send: wait.send.clone(), task: wait.task.clone() };
+      (wait, wait_handle)
+    }
+
+    pub fn try_wait(&self) -> Option<Result<T, Error>> {
+      self.recv.try_recv().ok()
+    }
+
+    pub fn wait(&self) -> Result<T, Error> {
+      self.recv.recv().unwrap()
+    }
+  }
+
+  impl<T> WaitHandle<T> {
+    pub fn finish(&self, val: T) {
+      let _ = self.send.send(Ok(val));
+      if let Some(task) = self.task.lock().take() {
+        task.notify();
+      }
+    }
+  }
+}
+
+fn same_thread(c: &mut Criterion) {
+  c.bench_function("legacy wait, same thread", |b| b.iter(|| {
+    let (wait, wait_handle) = legacy::Wait::new();
+    wait_handle.finish(42);
+    wait.try_wait()
+  }));
+  c.bench_function("wait, same thread", |b| b.iter(|| {
+    let (wait, wait_handle) = Wait::new();
+    wait_handle.finish(42);
+    wait.try_wait()
+  }));
+}
+
+fn other_thread(c: &mut Criterion) {
+  c.bench_function("legacy wait, other thread", |b| b.iter(|| {
+    let (wait, wait_handle) = legacy::Wait::new();
+    let thread = thread::spawn(move || wait_handle.finish(42));
+    let res = wait.wait();
+    thread.join().unwrap();
+    res
+  }));
+  c.bench_function("wait, other thread", |b| b.iter(|| {
+    let (wait, wait_handle) = Wait::new();
+    let thread = thread::spawn(move || wait_handle.finish(42));
+    let res = wait.wait();
+    thread.join().unwrap();
+    res
+  }));
+}
+
+criterion_group!(benches, same_thread, other_thread);
+criterion_main!(benches);
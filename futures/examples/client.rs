@@ -5,7 +5,7 @@ use env_logger;
 use failure::Error;
 use futures::{Future, Stream};
 use lapin_futures as lapin;
-use crate::lapin::{BasicProperties, Client, ConnectionProperties};
+use crate::lapin::{BasicProperties, Client, ConnectionProperties, ExchangeKind};
 use crate::lapin::options::{BasicConsumeOptions, BasicGetOptions, BasicPublishOptions, ExchangeBindOptions, ExchangeUnbindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, QueueBindOptions, QueueDeclareOptions};
 use crate::lapin::types::FieldTable;
 use log::{debug, info};
@@ -26,7 +26,7 @@ fn main() {
         channel.queue_declare("hello", QueueDeclareOptions::default(), FieldTable::default()).and_then(move |_| {
           info!("channel {} declared queue {}", id, "hello");
 
-          channel.exchange_declare("hello_exchange", "direct", ExchangeDeclareOptions::default(), FieldTable::default()).and_then(move |_| {
+          channel.exchange_declare("hello_exchange", ExchangeKind::Direct, ExchangeDeclareOptions::default(), FieldTable::default()).and_then(move |_| {
             channel.queue_bind("hello", "hello_exchange", "hello_2", QueueBindOptions::default(), FieldTable::default()).and_then(move |_| {
               channel.basic_publish(
                 "hello_exchange",
@@ -2,7 +2,7 @@ use env_logger;
 use failure::Error;
 use futures::Future;
 use lapin_futures as lapin;
-use crate::lapin::{BasicProperties, Client, ConnectionProperties};
+use crate::lapin::{BasicProperties, Client, ConnectionProperties, ExchangeKind};
 use crate::lapin::options::{BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
 use crate::lapin::types::FieldTable;
 use tokio;
@@ -18,7 +18,7 @@ fn main() {
         Client::connect(&addr, ConnectionProperties::default()).map_err(Error::from).and_then(|client| {
             client.create_channel()
                 .and_then(|channel| {
-                    channel.clone().exchange_declare("hello_topic", "topic", ExchangeDeclareOptions::default(), FieldTable::default()).map(move |_| channel)
+                    channel.clone().exchange_declare("hello_topic", ExchangeKind::Topic, ExchangeDeclareOptions::default(), FieldTable::default()).map(move |_| channel)
                 }).and_then(|channel| {
                     channel.clone().queue_declare("topic_queue", QueueDeclareOptions::default(), FieldTable::default()).map(move |_| channel)
                 }).and_then(|channel| {
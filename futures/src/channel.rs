@@ -1,8 +1,13 @@
 use futures::Future;
-use lapin::{Channel as InnerChannel, Connection};
+use lapin::{Bytes, Channel as InnerChannel, Connection};
+
+use std::{
+  borrow::Borrow,
+  time::Duration,
+};
 
 use crate::{
-  BasicProperties, ConfirmationFuture, Consumer, Error, Queue,
+  BasicProperties, CloseReason, ConfirmationFuture, Consumer, Error, ExchangeKind, PublisherConfirm, Queue, ReturnedMessageDelegate,
   message::{BasicGetMessage, BasicReturnMessage},
   options::*,
   types::{Boolean, FieldTable, LongUInt, ShortUInt},
@@ -18,13 +23,52 @@ impl Channel {
   /// create a channel
   pub fn create(conn: &Connection) -> impl Future<Item = Self, Error = Error> {
     let confirmation: ConfirmationFuture<InnerChannel> = conn.create_channel().into();
-    confirmation.map(|inner| Channel { inner })
+    confirmation.map(Channel::new)
+  }
+
+  pub(crate) fn new(inner: InnerChannel) -> Self {
+    Self { inner }
   }
 
   pub fn id(&self) -> u16 {
     self.inner.id()
   }
 
+  /// why the channel got closed, if it did
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.inner.close_reason()
+  }
+
+  /// registers a handler called once the channel is closed
+  pub fn on_close<C: Fn(CloseReason) + Send + 'static>(&self, handler: Box<C>) {
+    self.inner.on_close(handler)
+  }
+
+  /// registers a handler called if the channel gets closed because of an error
+  pub fn on_error<E: Fn(CloseReason) + Send + 'static>(&self, handler: Box<E>) {
+    self.inner.on_error(handler)
+  }
+
+  /// registers a handler called each time the channel got recovered after the server closed it
+  pub fn on_recovery<R: Fn() + Send + 'static>(&self, handler: Box<R>) {
+    self.inner.on_recovery(handler)
+  }
+
+  /// whether the server lets us publish on this channel
+  pub fn flow(&self) -> bool {
+    self.inner.flow()
+  }
+
+  /// returns a future that resolves once the server lets us publish on this channel again
+  pub fn wait_for_flow(&self) -> ConfirmationFuture<()> {
+    self.inner.wait_for_flow().into()
+  }
+
+  /// reopens the channel when the server closes it, restoring its confirm mode, qos and consumers
+  pub fn enable_recovery(&self) {
+    self.inner.enable_recovery()
+  }
+
   /// request access
   ///
   /// returns a future that resolves once the access is granted
@@ -35,8 +79,8 @@ impl Channel {
   /// declares an exchange
   ///
   /// returns a future that resolves once the exchange is available
-  pub fn exchange_declare(&self, name: &str, exchange_type: &str, options: ExchangeDeclareOptions, arguments: FieldTable) -> ConfirmationFuture<()> {
-    self.inner.exchange_declare(name, exchange_type, options, arguments).into()
+  pub fn exchange_declare(&self, name: &str, kind: ExchangeKind, options: ExchangeDeclareOptions, arguments: FieldTable) -> ConfirmationFuture<()> {
+    self.inner.exchange_declare(name, kind, options, arguments).into()
   }
 
   /// deletes an exchange
@@ -95,17 +139,31 @@ impl Channel {
   }
 
   /// publishes a message on a queue
-  pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, options: BasicPublishOptions, properties: BasicProperties) -> ConfirmationFuture<()> {
+  ///
+  /// in confirm mode, the future resolves once the server acked or nacked the message
+  pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: impl Into<Bytes>, options: BasicPublishOptions, properties: BasicProperties) -> ConfirmationFuture<PublisherConfirm> {
     self.inner.basic_publish(exchange, routing_key, options, payload, properties).into()
   }
 
+  /// publishes a message on a queue, giving up on its confirm after `timeout`
+  ///
+  /// in confirm mode, the future fails if the server neither acked nor nacked the message in time
+  pub fn basic_publish_with_timeout(&self, exchange: &str, routing_key: &str, payload: impl Into<Bytes>, options: BasicPublishOptions, properties: BasicProperties, timeout: Duration) -> ConfirmationFuture<PublisherConfirm> {
+    self.inner.basic_publish_with_timeout(exchange, routing_key, options, payload, properties, timeout).into()
+  }
+
+  /// publishes a message on a queue, failing if too many frames are already waiting to be sent
+  pub fn try_basic_publish(&self, exchange: &str, routing_key: &str, payload: impl Into<Bytes>, options: BasicPublishOptions, properties: BasicProperties) -> ConfirmationFuture<PublisherConfirm> {
+    self.inner.try_basic_publish(exchange, routing_key, options, payload, properties).into()
+  }
+
   /// creates a consumer stream
   ///
   /// returns a future of a `Consumer` that resolves once the method succeeds
   ///
   /// `Consumer` implements `futures::Stream`, so it can be used with any of
   /// the usual combinators
-  pub fn basic_consume(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer, Error = Error> {
+  pub fn basic_consume<Q: Borrow<str> + ?Sized>(&self, queue: &Q, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer, Error = Error> {
     let confirmation: ConfirmationFuture<lapin::Consumer> = self.inner.basic_consume(queue, consumer_tag, options, arguments).into();
     confirmation.map(|consumer| Consumer(consumer))
   }
@@ -189,4 +247,9 @@ impl Channel {
   pub fn wait_for_confirms(&self) -> ConfirmationFuture<Vec<BasicReturnMessage>> {
     self.inner.wait_for_confirms().into()
   }
+
+  /// sets the delegate receiving the messages returned by the server
+  pub fn on_return(&self, delegate: Box<dyn ReturnedMessageDelegate>) {
+    self.inner.on_return(delegate)
+  }
 }
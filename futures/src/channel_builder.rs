@@ -0,0 +1,62 @@
+use futures::Future;
+use lapin::{Channel as InnerChannel, ChannelBuilder as InnerChannelBuilder};
+
+use crate::{
+  Channel, CloseReason, ConfirmationFuture, Error, ReturnedMessageDelegate,
+  options::{BasicQosOptions, ChannelFlowOptions, ConfirmSelectOptions},
+  types::ShortUInt,
+};
+
+/// Open a channel with its confirm mode, qos, flow and handlers already set up
+///
+/// If one of the steps fails, the channel gets closed and the future resolves to the first error
+pub struct ChannelBuilder {
+  inner: InnerChannelBuilder,
+}
+
+impl ChannelBuilder {
+  pub(crate) fn new(inner: InnerChannelBuilder) -> Self {
+    Self { inner }
+  }
+
+  /// puts the channel in confirm mode
+  pub fn confirm_select(self, options: ConfirmSelectOptions) -> Self {
+    Self::new(self.inner.confirm_select(options))
+  }
+
+  /// sets the prefetch of the channel
+  pub fn basic_qos(self, prefetch_count: ShortUInt, options: BasicQosOptions) -> Self {
+    Self::new(self.inner.basic_qos(prefetch_count, options))
+  }
+
+  /// asks the server to pause or resume the deliveries on the channel
+  pub fn channel_flow(self, options: ChannelFlowOptions) -> Self {
+    Self::new(self.inner.channel_flow(options))
+  }
+
+  /// reopens the channel when the server closes it, restoring its confirm mode, qos and consumers
+  pub fn enable_recovery(self) -> Self {
+    Self::new(self.inner.enable_recovery())
+  }
+
+  /// sets the delegate receiving the messages the server returns
+  pub fn on_return(self, delegate: Box<dyn ReturnedMessageDelegate>) -> Self {
+    Self::new(self.inner.on_return(delegate))
+  }
+
+  /// registers a handler called once the channel is closed
+  pub fn on_close<C: Fn(CloseReason) + Send + 'static>(self, handler: Box<C>) -> Self {
+    Self::new(self.inner.on_close(handler))
+  }
+
+  /// registers a handler called if the channel gets closed because of an error
+  pub fn on_error<E: Fn(CloseReason) + Send + 'static>(self, handler: Box<E>) -> Self {
+    Self::new(self.inner.on_error(handler))
+  }
+
+  /// returns a future that resolves to the configured `Channel`
+  pub fn build(self) -> impl Future<Item = Channel, Error = Error> + Send + 'static {
+    let confirmation: ConfirmationFuture<InnerChannel> = self.inner.build().into();
+    confirmation.map(Channel::new)
+  }
+}
@@ -5,7 +5,7 @@ use lapin::{
 };
 
 use crate::{
-  Channel, ConfirmationFuture, ConnectionProperties, Error,
+  Channel, ChannelBuilder, ConfirmationFuture, ConnectionProperties, Error, Queue,
   uri::AMQPUri,
 };
 
@@ -31,6 +31,23 @@ impl Client {
     Channel::create(&self.conn)
   }
 
+  /// Return a builder opening a channel with its confirm mode, qos and handlers set up in one go
+  pub fn channel_builder(&self) -> ChannelBuilder {
+    ChannelBuilder::new(self.conn.channel_builder())
+  }
+
+  /// Return a future that resolves to the queue if it exists, or None,
+  /// without closing any of the channels
+  pub fn queue_exists(&self, name: &str) -> ConfirmationFuture<Option<Queue>> {
+    self.conn.queue_exists(name).into()
+  }
+
+  /// Return a future that resolves to whether the exchange exists,
+  /// without closing any of the channels
+  pub fn exchange_exists(&self, name: &str) -> ConfirmationFuture<bool> {
+    self.conn.exchange_exists(name).into()
+  }
+
   /// Register an error handler which will be called when connection reaches an Error state
   pub fn on_error<E: Fn() + Send + 'static>(&self, handler: Box<E>) {
     self.conn.on_error(handler);
@@ -100,15 +100,17 @@
 
 pub use lapin::{
   auth, message, options, protocol, tcp, types, uri,
-  BasicProperties, Configuration, ConnectionProperties, ConsumerDelegate, Error, ErrorKind, Queue,
+  BasicProperties, CloseReason, Configuration, ConnectionProperties, ConsumerDelegate, Error, ErrorKind, ExchangeArguments, ExchangeKind, HeadersBinding, HeadersMatch, Overflow, PublisherConfirm, Queue, QueueArguments, QueueType, ReturnedMessageDelegate,
 };
 
 pub use channel::Channel;
+pub use channel_builder::ChannelBuilder;
 pub use client::{Client, ClientFuture, Connect};
 pub use confirmation::ConfirmationFuture;
 pub use consumer::Consumer;
 
 mod channel;
+mod channel_builder;
 mod client;
 mod confirmation;
 mod consumer;
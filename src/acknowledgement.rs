@@ -1,125 +1,330 @@
 use parking_lot::Mutex;
 
 use std::{
-  collections::{HashMap, HashSet},
+  collections::{BTreeMap, BTreeSet},
+  mem,
   sync::Arc,
 };
 
 use crate::{
   error::{Error, ErrorKind},
-  returned_messages::ReturnedMessages,
+  message::BasicReturnMessage,
+  publisher_confirm::PublisherConfirm,
   wait::{Wait, WaitHandle},
 };
 
 pub type DeliveryTag = u64;
 
-#[derive(Debug, Clone)]
-pub(crate) struct Acknowledgements {
+#[derive(Debug, Clone, Default)]
+pub struct Acknowledgements {
   inner: Arc<Mutex<Inner>>,
 }
 
 impl Acknowledgements {
-  pub(crate) fn new(returned_messages: ReturnedMessages) -> Self {
-    Self { inner: Arc::new(Mutex::new(Inner::new(returned_messages))) }
+  pub fn register_pending(&self, delivery_tag: DeliveryTag) -> Wait<PublisherConfirm> {
+    self.inner.lock().register_pending(delivery_tag)
   }
 
-  pub(crate) fn register_pending(&self, delivery_tag: DeliveryTag) {
-    self.inner.lock().register_pending(delivery_tag);
+  pub(crate) fn pending(&self) -> Vec<DeliveryTag> {
+    self.inner.lock().pending.keys().cloned().collect()
   }
 
-  pub(crate) fn get_last_pending(&self) -> Option<Wait<()>> {
-    self.inner.lock().last.take()
+  /// Keep track of the sending of a publish, so that we can tell it wasn't sent if the channel dies first
+  pub(crate) fn sending(&self, delivery_tag: DeliveryTag, send: Wait<()>) {
+    if let Some(pending) = self.inner.lock().pending.get_mut(&delivery_tag) {
+      pending.send = Some(send);
+    }
+  }
+
+  /// Wait for all the currently pending confirms
+  pub(crate) fn wait_for_pending(&self) -> Wait<()> {
+    let (wait, wait_handle) = Wait::new();
+    let mut inner = self.inner.lock();
+    if let Some(last) = inner.pending.keys().next_back().cloned() {
+      inner.waiters.push((last, wait_handle));
+    } else {
+      wait_handle.finish(());
+    }
+    wait
+  }
+
+  /// Give up on the confirm of a publish, if it is still pending
+  pub(crate) fn timeout(&self, delivery_tag: DeliveryTag) {
+    self.inner.lock().timeout(delivery_tag);
+  }
+
+  /// Hold a message returned by the server until the confirm of the publish it comes from
+  pub(crate) fn returned(&self, message: BasicReturnMessage) {
+    self.inner.lock().returned(message);
   }
 
   pub(crate) fn ack(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    self.inner.lock().ack(delivery_tag)
+    self.inner.lock().drop_pending(delivery_tag, true)
   }
 
   pub(crate) fn nack(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    self.inner.lock().nack(delivery_tag)
+    self.inner.lock().drop_pending(delivery_tag, false)
   }
 
   pub(crate) fn ack_all_pending(&self) {
-    let mut inner = self.inner.lock();
-    for wait in inner.drain_pending() {
-      wait.finish(());
-    }
+    self.inner.lock().drop_all_pending(None, true);
   }
 
   pub(crate) fn nack_all_pending(&self) {
-    let mut inner = self.inner.lock();
-    for wait in inner.drain_pending() {
-      wait.finish(());
-    }
+    self.inner.lock().drop_all_pending(None, false);
   }
 
-  pub(crate) fn ack_all_before(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
+  /// The channel died, we won't get any confirm anymore
+  pub(crate) fn fail_all_pending<F: Fn() -> ErrorKind>(&self, error: F) {
     let mut inner = self.inner.lock();
-    for tag in inner.list_pending_before(delivery_tag) {
-      inner.ack(tag)?;
+    inner.returned = None;
+    inner.timed_out.clear();
+    for (_, pending) in mem::replace(&mut inner.pending, BTreeMap::default()) {
+      pending.fail(error());
+    }
+    for (_, wait_handle) in inner.waiters.drain(..) {
+      wait_handle.error(error().into());
     }
+  }
+
+  pub fn ack_all_before(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.inner.lock().drop_all_pending(Some(delivery_tag), true);
     Ok(())
   }
 
   pub(crate) fn nack_all_before(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    let mut inner = self.inner.lock();
-    for tag in inner.list_pending_before(delivery_tag) {
-      inner.nack(tag)?;
-    }
+    self.inner.lock().drop_all_pending(Some(delivery_tag), false);
     Ok(())
   }
 }
 
 #[derive(Debug)]
+struct Pending {
+  wait_handle: WaitHandle<PublisherConfirm>,
+  send:        Option<Wait<()>>,
+}
+
+impl Pending {
+  fn finish(self, success: bool, returned: Option<BasicReturnMessage>) {
+    self.wait_handle.finish(if success { PublisherConfirm::Ack(returned) } else { PublisherConfirm::Nack(returned) });
+  }
+
+  fn fail(self, error: ErrorKind) {
+    // If the message couldn't be sent, this error carries its payload
+    let error = match self.send.and_then(|send| send.try_wait()) {
+      Some(Err(send_error)) => send_error,
+      _                     => error.into(),
+    };
+    self.wait_handle.error(error);
+  }
+}
+
+#[derive(Debug, Default)]
 struct Inner {
-  last:              Option<Wait<()>>,
-  pending:           HashMap<DeliveryTag, WaitHandle<()>>,
-  returned_messages: ReturnedMessages,
+  pending:  BTreeMap<DeliveryTag, Pending>,
+  /// Waiting for all the confirms up to the given delivery tag
+  waiters:  Vec<(DeliveryTag, WaitHandle<()>)>,
+  /// The server sends basic.return right before the confirm of the returned publish
+  returned:  Option<BasicReturnMessage>,
+  /// The publishes we gave up on, the server can still confirm them
+  timed_out: BTreeSet<DeliveryTag>,
 }
 
 impl Inner {
-  fn new(returned_messages: ReturnedMessages) -> Self {
-    Self {
-      last:    None,
-      pending: HashMap::default(),
-      returned_messages,
+  fn register_pending(&mut self, delivery_tag: DeliveryTag) -> Wait<PublisherConfirm> {
+    let (wait, wait_handle) = Wait::new();
+    self.pending.insert(delivery_tag, Pending { wait_handle, send: None });
+    wait
+  }
+
+  fn timeout(&mut self, delivery_tag: DeliveryTag) {
+    if let Some(pending) = self.pending.remove(&delivery_tag) {
+      self.timed_out.insert(delivery_tag);
+      pending.wait_handle.error(ErrorKind::ConfirmTimeout.into());
+      self.notify_waiters();
     }
   }
 
-  fn register_pending(&mut self, delivery_tag: DeliveryTag) {
-    let (wait, wait_handle) = Wait::new();
-    self.pending.insert(delivery_tag, wait_handle);
-    self.last = Some(wait);
+  fn returned(&mut self, message: BasicReturnMessage) {
+    // Outside of confirm mode, there is no confirm to attach the message to
+    if !self.pending.is_empty() {
+      self.returned = Some(message);
+    }
   }
 
   fn drop_pending(&mut self, delivery_tag: DeliveryTag, success: bool) -> Result<(), Error> {
-    if let Some(delivery_wait) =  self.pending.remove(&delivery_tag) {
-      if success {
-        delivery_wait.finish(());
-      } else {
-        self.returned_messages.register_waiter(delivery_wait);
-      }
+    if let Some(pending) = self.pending.remove(&delivery_tag) {
+      pending.finish(success, self.returned.take());
+      self.notify_waiters();
+      Ok(())
+    } else if self.timed_out.remove(&delivery_tag) {
+      // Nobody waits for this confirm anymore
+      self.returned = None;
       Ok(())
     } else {
       Err(ErrorKind::PreconditionFailed.into())
     }
   }
 
-  fn ack(&mut self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    self.drop_pending(delivery_tag, true)?;
-    Ok(())
+  /// Remove the pending confirms up to `delivery_tag` included, without going through the other ones
+  fn drop_all_pending(&mut self, delivery_tag: Option<DeliveryTag>, success: bool) {
+    let (after, timed_out_after) = match delivery_tag.and_then(|tag| tag.checked_add(1)) {
+      Some(next) => (self.pending.split_off(&next), self.timed_out.split_off(&next)),
+      None       => (BTreeMap::default(), BTreeSet::default()),
+    };
+    // The returned message belongs to the publish being confirmed, the last one of the range
+    let released  = mem::replace(&mut self.pending, after);
+    let timed_out = mem::replace(&mut self.timed_out, timed_out_after);
+    let last      = released.keys().next_back().cloned();
+    if timed_out.iter().next_back().cloned() > last {
+      // It belongs to a publish we gave up on
+      self.returned = None;
+    }
+    for (delivery_tag, pending) in released {
+      let returned = if Some(delivery_tag) == last { self.returned.take() } else { None };
+      pending.finish(success, returned);
+    }
+    self.notify_waiters();
   }
 
-  fn nack(&mut self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    self.drop_pending(delivery_tag, false)?;
-    Ok(())
+  fn notify_waiters(&mut self) {
+    let first_pending = self.pending.keys().next().cloned();
+    let (done, waiting): (Vec<_>, Vec<_>) = self.waiters.drain(..).partition(|(tag, _)| first_pending.map_or(true, |first| *tag < first));
+    self.waiters = waiting;
+    for (_, wait_handle) in done {
+      wait_handle.finish(());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use bytes::Bytes;
+
+  fn returned(exchange: &str, routing_key: &str) -> BasicReturnMessage {
+    BasicReturnMessage::new(exchange.into(), routing_key.into(), 312, "NO_ROUTE".into())
+  }
+
+  #[test]
+  fn each_publish_gets_its_own_outcome() {
+    let acknowledgements = Acknowledgements::default();
+    let first            = acknowledgements.register_pending(1);
+    let second           = acknowledgements.register_pending(2);
+    let third            = acknowledgements.register_pending(3);
+    acknowledgements.nack(2).unwrap();
+    acknowledgements.ack_all_before(3).unwrap();
+    assert_eq!(first.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert_eq!(second.try_wait().unwrap().unwrap(), PublisherConfirm::Nack(None));
+    assert_eq!(third.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert!(acknowledgements.ack(3).is_err());
+  }
+
+  #[test]
+  fn returned_messages_go_to_the_next_confirm() {
+    let acknowledgements = Acknowledgements::default();
+    let first            = acknowledgements.register_pending(1);
+    let second           = acknowledgements.register_pending(2);
+    let third            = acknowledgements.register_pending(3);
+    let fourth           = acknowledgements.register_pending(4);
+    // The same route for all the publishes, only the confirm order tells them apart
+    acknowledgements.returned(returned("exchange", "key"));
+    acknowledgements.ack(2).unwrap();
+    acknowledgements.ack(1).unwrap();
+    acknowledgements.returned(returned("exchange", "key"));
+    acknowledgements.nack_all_before(4).unwrap();
+    assert_eq!(first.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert_eq!(second.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(Some(returned("exchange", "key"))));
+    assert_eq!(third.try_wait().unwrap().unwrap(), PublisherConfirm::Nack(None));
+    assert_eq!(fourth.try_wait().unwrap().unwrap(), PublisherConfirm::Nack(Some(returned("exchange", "key"))));
+  }
+
+  #[test]
+  fn returned_messages_are_ignored_outside_of_confirm_mode() {
+    let acknowledgements = Acknowledgements::default();
+    acknowledgements.returned(returned("exchange", "key"));
+    let publish          = acknowledgements.register_pending(1);
+    acknowledgements.ack(1).unwrap();
+    assert_eq!(publish.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+  }
+
+  #[test]
+  fn waiting_for_pending_confirms() {
+    let acknowledgements = Acknowledgements::default();
+    assert!(acknowledgements.wait_for_pending().try_wait().is_some());
+    acknowledgements.register_pending(1);
+    acknowledgements.register_pending(2);
+    let wait = acknowledgements.wait_for_pending();
+    acknowledgements.register_pending(3);
+    acknowledgements.ack(1).unwrap();
+    assert!(wait.try_wait().is_none());
+    acknowledgements.ack(2).unwrap();
+    assert!(wait.try_wait().is_some());
+  }
+
+  #[test]
+  fn unsent_publishes_fail_with_their_payload() {
+    let acknowledgements    = Acknowledgements::default();
+    let (send, send_handle) = Wait::new();
+    let unsent              = acknowledgements.register_pending(1);
+    let sent                = acknowledgements.register_pending(2);
+    acknowledgements.sending(1, send);
+    send_handle.error(ErrorKind::MessageNotSent(Bytes::from_static(b"payload"), Box::new(ErrorKind::NotConnected)).into());
+    acknowledgements.fail_all_pending(|| ErrorKind::NotConnected);
+    assert_eq!(unsent.try_wait().unwrap().unwrap_err().unsent_payload(), Some(Bytes::from_static(b"payload")));
+    assert!(sent.try_wait().unwrap().unwrap_err().unsent_payload().is_none());
+    assert!(acknowledgements.pending().is_empty());
   }
 
-  fn drain_pending(&mut self) -> Vec<WaitHandle<()>> {
-    self.pending.drain().map(|tup| tup.1).collect()
+  #[test]
+  fn timed_out_confirms_are_given_up_on() {
+    let acknowledgements = Acknowledgements::default();
+    let first            = acknowledgements.register_pending(1);
+    let second           = acknowledgements.register_pending(2);
+    let third            = acknowledgements.register_pending(3);
+    let wait             = acknowledgements.wait_for_pending();
+    acknowledgements.ack(1).unwrap();
+    acknowledgements.timeout(1);
+    acknowledgements.timeout(2);
+    acknowledgements.timeout(3);
+    assert_eq!(first.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert!(match second.try_wait().unwrap().unwrap_err().kind() {
+      ErrorKind::ConfirmTimeout => true,
+      _                         => false,
+    });
+    assert!(third.try_wait().unwrap().is_err());
+    assert!(acknowledgements.pending().is_empty());
+    assert!(wait.try_wait().is_some());
+    // The late confirms are ignored, along with the message returned with them
+    acknowledgements.returned(returned("exchange", "key"));
+    acknowledgements.nack(2).unwrap();
+    let fourth           = acknowledgements.register_pending(4);
+    acknowledgements.returned(returned("exchange", "key"));
+    acknowledgements.ack_all_before(3).unwrap();
+    acknowledgements.ack(4).unwrap();
+    assert_eq!(fourth.try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert!(acknowledgements.ack(3).is_err());
   }
 
-  fn list_pending_before(&mut self, delivery_tag: DeliveryTag) -> HashSet<DeliveryTag> {
-    self.pending.iter().map(|tup| tup.0).filter(|tag| **tag <= delivery_tag).cloned().collect()
+  #[test]
+  fn multiple_confirms_release_a_range() {
+    let acknowledgements = Acknowledgements::default();
+    let confirms         = (1..=6).map(|tag| acknowledgements.register_pending(tag)).collect::<Vec<_>>();
+    let wait             = acknowledgements.wait_for_pending();
+    acknowledgements.ack_all_before(2).unwrap();
+    acknowledgements.nack_all_before(4).unwrap();
+    assert_eq!(acknowledgements.pending(), vec![5, 6]);
+    assert_eq!(confirms[1].try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert_eq!(confirms[3].try_wait().unwrap().unwrap(), PublisherConfirm::Nack(None));
+    assert!(confirms[4].try_wait().is_none());
+    assert!(wait.try_wait().is_none());
+    // Already released tags are no longer pending, this releases nothing
+    acknowledgements.ack_all_before(3).unwrap();
+    assert_eq!(acknowledgements.pending(), vec![5, 6]);
+    acknowledgements.ack_all_before(DeliveryTag::max_value()).unwrap();
+    assert!(acknowledgements.pending().is_empty());
+    assert_eq!(confirms[5].try_wait().unwrap().unwrap(), PublisherConfirm::Ack(None));
+    assert!(wait.try_wait().is_some());
   }
 }
@@ -1,73 +1,138 @@
-use std::{cmp, ptr};
+use std::cmp;
 
+use crate::diagnostics::BufferDiagnostics;
+
+/// A ring buffer whose memory can grow up to `max_capacity`
 #[derive(Debug,PartialEq,Clone)]
 pub(crate) struct Buffer {
-  memory:   Vec<u8>,
-  capacity: usize,
-  position: usize,
-  end:      usize
+  memory:       Vec<u8>,
+  capacity:     usize,
+  max_capacity: usize,
+  position:     usize,
+  length:       usize,
 }
 
 impl Buffer {
-  pub(crate) fn with_capacity(capacity: usize) -> Buffer {
+  pub(crate) fn with_capacity(capacity: usize, max_capacity: usize) -> Buffer {
+    let capacity = cmp::min(capacity, max_capacity);
     Buffer {
       memory:   vec![0; capacity],
       capacity,
+      max_capacity,
       position: 0,
-      end:      0
+      length:   0,
     }
   }
 
+  pub(crate) fn capacity(&self) -> usize {
+    self.capacity
+  }
+
   pub(crate) fn grow(&mut self, new_size: usize) -> bool {
+    let new_size = cmp::min(new_size, self.max_capacity);
     if self.capacity >= new_size {
       return false;
     }
 
+    self.make_contiguous();
     self.memory.resize(new_size, 0);
     self.capacity = new_size;
     true
   }
 
   pub(crate) fn available_data(&self) -> usize {
-    self.end - self.position
+    self.length
   }
 
   pub(crate) fn available_space(&self) -> usize {
-    self.capacity - self.end
+    self.capacity - self.length
   }
 
   pub(crate) fn consume(&mut self, count: usize) -> usize {
-    let cnt        = cmp::min(count, self.available_data());
-    self.position += cnt;
+    let cnt      = cmp::min(count, self.available_data());
+    self.length -= cnt;
+    if self.length == 0 {
+      // Nothing left, start over from the beginning to get the biggest contiguous space
+      self.position = 0;
+    } else {
+      self.position = (self.position + cnt) % self.capacity;
+    }
     cnt
   }
 
   pub(crate) fn fill(&mut self, count: usize) -> usize {
-    let cnt   = cmp::min(count, self.available_space());
-    self.end += cnt;
+    let cnt      = cmp::min(count, self.available_space());
+    self.length += cnt;
     cnt
   }
 
+  /// Copy as much of `data` as possible at the end of the buffer, wrapping around if needed
+  pub(crate) fn push(&mut self, data: &[u8]) -> usize {
+    let mut written = 0;
+    while written < data.len() {
+      let space = self.space();
+      let cnt   = cmp::min(space.len(), data.len() - written);
+      if cnt == 0 {
+        break;
+      }
+      space[..cnt].copy_from_slice(&data[written..written + cnt]);
+      written += self.fill(cnt);
+    }
+    written
+  }
+
+  /// Copy the beginning of the available data to `dest`, wrapping around if needed, without consuming it
+  pub(crate) fn copy_data(&self, dest: &mut [u8]) -> usize {
+    let cnt = cmp::min(dest.len(), self.available_data());
+    for (i, byte) in dest[..cnt].iter_mut().enumerate() {
+      *byte = self.memory[(self.position + i) % self.capacity];
+    }
+    cnt
+  }
+
+  /// The first contiguous part of the available data
   pub(crate) fn data(&self) -> &[u8] {
-    &self.memory[self.position..self.end]
+    let end = cmp::min(self.position + self.length, self.capacity);
+    &self.memory[self.position..end]
+  }
+
+  /// The available data, as the two contiguous parts it can be split in
+  pub(crate) fn data_slices(&self) -> (&[u8], &[u8]) {
+    let end = self.position + self.length;
+    if end <= self.capacity {
+      (&self.memory[self.position..end], &[])
+    } else {
+      (&self.memory[self.position..], &self.memory[..end - self.capacity])
+    }
   }
 
+  /// The first contiguous part of the available space
   pub(crate) fn space(&mut self) -> &mut [u8] {
-    &mut self.memory[self.end..self.capacity]
+    let end = self.position + self.length;
+    if end < self.capacity {
+      &mut self.memory[end..self.capacity]
+    } else {
+      let end = end - self.capacity;
+      &mut self.memory[end..self.position]
+    }
+  }
+
+  pub(crate) fn is_contiguous(&self) -> bool {
+    self.data().len() == self.available_data()
   }
 
-  pub(crate) fn shift(&mut self) {
-    let length = self.end - self.position;
-    unsafe {
-      ptr::copy((&self.memory[self.position..self.end]).as_ptr(), (&mut self.memory[..length]).as_mut_ptr(), length);
+  /// Move the available data at the beginning of the memory so that `data()` returns all of it
+  pub(crate) fn make_contiguous(&mut self) {
+    if self.position != 0 {
+      self.memory.rotate_left(self.position);
+      self.position = 0;
     }
-    self.position = 0;
-    self.end      = length;
   }
 
-  pub(crate) fn shift_unless_available(&mut self, size: usize) {
-    if self.available_space() < size {
-      self.shift();
+  pub(crate) fn diagnostics(&self) -> BufferDiagnostics {
+    BufferDiagnostics {
+      capacity:       self.capacity,
+      available_data: self.available_data(),
     }
   }
 }
@@ -79,7 +144,7 @@ mod tests {
 
   #[test]
   fn fill_and_consume() {
-    let mut b = Buffer::with_capacity(10);
+    let mut b = Buffer::with_capacity(10, 10);
     assert_eq!(b.available_data(), 0);
     assert_eq!(b.available_space(), 10);
     let res = b.space().write(&b"abcd"[..]).map(|size| { b.fill(size); size });
@@ -91,21 +156,58 @@ mod tests {
 
     b.consume(2);
     assert_eq!(b.available_data(), 2);
-    assert_eq!(b.available_space(), 6);
-    assert_eq!(b.data(), &b"cd"[..]);
-
-    b.shift();
-    assert_eq!(b.available_data(), 2);
     assert_eq!(b.available_space(), 8);
     assert_eq!(b.data(), &b"cd"[..]);
 
-    assert_eq!(b.space().write(&b"efghijklmnop"[..]).map(|size| { b.fill(size); size }).ok(), Some(8));
+    assert_eq!(b.space().write(&b"efghijklmnop"[..]).map(|size| { b.fill(size); size }).ok(), Some(6));
+    assert_eq!(b.available_data(), 8);
+    assert_eq!(b.available_space(), 2);
+    assert_eq!(b.data(), &b"cdefghij"[..]);
+
+    assert_eq!(b.space().write(&b"klmnop"[..]).map(|size| { b.fill(size); size }).ok(), Some(2));
     assert_eq!(b.available_data(), 10);
     assert_eq!(b.available_space(), 0);
-    assert_eq!(b.data(), &b"cdefghijkl"[..]);
-    b.shift();
+    assert_eq!(b.data(), &b"cdefghij"[..]);
+    assert!(!b.is_contiguous());
+
+    b.make_contiguous();
     assert_eq!(b.available_data(), 10);
     assert_eq!(b.available_space(), 0);
     assert_eq!(b.data(), &b"cdefghijkl"[..]);
+
+    b.consume(10);
+    assert_eq!(b.available_data(), 0);
+    assert_eq!(b.available_space(), 10);
+  }
+
+  #[test]
+  fn push_and_copy_wrapping() {
+    let mut b = Buffer::with_capacity(8, 8);
+    assert_eq!(b.push(&b"abcdef"[..]), 6);
+    b.consume(4);
+    assert_eq!(b.push(&b"ghijklmn"[..]), 6);
+    assert_eq!(b.available_data(), 8);
+    assert_eq!(b.data(), &b"efgh"[..]);
+
+    let mut dest = [0; 6];
+    assert_eq!(b.copy_data(&mut dest), 6);
+    assert_eq!(&dest, b"efghij");
+    assert_eq!(b.available_data(), 8);
+  }
+
+  #[test]
+  fn grow_is_bounded() {
+    let mut b = Buffer::with_capacity(32, 16);
+    assert_eq!(b.capacity(), 16);
+    assert!(!b.grow(64));
+    assert_eq!(b.capacity(), 16);
+
+    let mut b = Buffer::with_capacity(4, 16);
+    b.push(&b"abcd"[..]);
+    b.consume(2);
+    b.push(&b"ef"[..]);
+    assert!(b.grow(64));
+    assert_eq!(b.capacity(), 16);
+    assert_eq!(b.data(), &b"cdef"[..]);
   }
 }
@@ -1,54 +1,85 @@
 use amq_protocol::frame::{AMQPContentHeader, AMQPFrame};
+use bytes::Bytes;
 use log::{debug, error, info, trace};
-
-use std::borrow::Borrow;
+use parking_lot::Mutex;
+
+use std::{
+  borrow::Borrow,
+  cmp,
+  sync::{
+    Arc,
+    atomic::{AtomicU16, Ordering},
+  },
+  collections::VecDeque,
+  time::Duration,
+};
 
 use crate::{
   BasicProperties,
   acknowledgement::{Acknowledgements, DeliveryTag},
   auth::Credentials,
+  channel_recovery::{ChannelRecovery, ConsumerSpec},
   channel_status::{ChannelStatus, ChannelState},
-  confirmation::Confirmation,
+  close_handler::CloseHandler,
+  close_reason::CloseReason,
+  confirmation::{Confirmation, Step, sequence},
   connection::Connection,
   connection_status::ConnectionState,
   consumer::Consumer,
+  diagnostics::ChannelDiagnostics,
   error::{Error, ErrorKind},
-  frames::Priority,
+  exchange::ExchangeKind,
+  frames::{OutgoingFrame, Priority},
   id_sequence::IdSequence,
   message::{BasicGetMessage, BasicReturnMessage, Delivery},
-  protocol::{self, AMQPClass, AMQPError, AMQPSoftError},
+  protocol::{self, AMQPClass, AMQPError, AMQPHardError, AMQPSoftError},
+  publisher_confirm::PublisherConfirm,
   queue::Queue,
   queues::Queues,
-  returned_messages::ReturnedMessages,
+  returned_messages::{ReturnedMessageDelegate, ReturnedMessages},
+  transaction::TransactionGuard,
   types::*,
   wait::{Wait, WaitHandle},
 };
 
+#[cfg(feature = "futures")]
+use std::future::Future;
+
+#[cfg(feature = "futures")]
+use crate::transaction::futures::TransactionFuture;
+
 #[cfg(test)]
 use crate::queue::QueueState;
 
 #[derive(Clone, Debug)]
 pub struct Channel {
-  id:                u16,
+  /// Shared by all the clones, as the channel gets a new id when it's recovered
+  id:                Arc<AtomicU16>,
   connection:        Connection,
   status:            ChannelStatus,
   acknowledgements:  Acknowledgements,
   delivery_tag:      IdSequence<DeliveryTag>,
   queues:            Queues,
   returned_messages: ReturnedMessages,
+  close_handler:     CloseHandler,
+  recovery:          ChannelRecovery,
+  /// Held while assigning a delivery tag and queuing the frames of a publish
+  publish_lock:      Arc<Mutex<()>>,
 }
 
 impl Channel {
   pub(crate) fn new(channel_id: u16, connection: Connection) -> Channel {
-    let returned_messages = ReturnedMessages::default();
     Channel {
-      id:               channel_id,
+      id:                Arc::new(AtomicU16::new(channel_id)),
       connection,
-      status:           ChannelStatus::default(),
-      acknowledgements: Acknowledgements::new(returned_messages.clone()),
-      delivery_tag:     IdSequence::new(false),
-      queues:           Queues::default(),
-      returned_messages,
+      status:            ChannelStatus::default(),
+      acknowledgements:  Acknowledgements::default(),
+      delivery_tag:      IdSequence::new(false),
+      queues:            Queues::default(),
+      returned_messages: ReturnedMessages::default(),
+      close_handler:     CloseHandler::default(),
+      recovery:          ChannelRecovery::default(),
+      publish_lock:      Arc::new(Mutex::new(())),
     }
   }
 
@@ -56,18 +87,82 @@ impl Channel {
     &self.status
   }
 
+  /// Why the channel got closed, by the server, by us or along with the connection
+  ///
+  /// None while the channel is open
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.status.close_reason()
+  }
+
+  /// Register a handler called once the channel is closed, whatever the reason
+  pub fn on_close<C: Fn(CloseReason) + Send + 'static>(&self, handler: Box<C>) {
+    self.close_handler.set_close_handler(handler);
+  }
+
+  /// Register a handler called if the channel gets closed because of an error,
+  /// e.g. when the server refuses a method with `PRECONDITION_FAILED`
+  pub fn on_error<E: Fn(CloseReason) + Send + 'static>(&self, handler: Box<E>) {
+    self.close_handler.set_error_handler(handler);
+  }
+
+  /// Register a handler called each time the channel got recovered, see `enable_recovery`
+  pub fn on_recovery<R: Fn() + Send + 'static>(&self, handler: Box<R>) {
+    self.close_handler.set_recovery_handler(handler);
+  }
+
+  /// Open the channel again under a new id when the server closes it, restoring its confirm mode,
+  /// its qos and its consumers. All the clones of this `Channel` keep working with the new one.
+  ///
+  /// The attempts are retried a few times with an increasing delay. The close and error handlers
+  /// are only called if the recovery fails, the recovery handler once it succeeded.
+  /// The connection errors still close the channel for good. The deliveries received before the
+  /// channel got closed can't be acked anymore, the server requeues them.
+  pub fn enable_recovery(&self) {
+    self.recovery.enable();
+  }
+
   pub(crate) fn set_closing(&self) {
     self.set_state(ChannelState::Closing);
   }
 
   pub(crate) fn set_closed(&self) -> Result<(), Error> {
+    let reason = self.status.set_close_reason(CloseReason::normal());
     self.set_state(ChannelState::Closed);
-    self.connection.remove_channel(self.id)
+    self.fail_pending(|| reason.channel_closed());
+    let res = self.connection.remove_channel(self.id());
+    // While recovering, the handlers only learn about the outcome of the recovery
+    if !self.recovery.is_recovering() {
+      self.close_handler.on_close(&reason);
+    }
+    res
   }
 
-  pub(crate) fn set_error(&self) -> Result<(), Error> {
+  pub(crate) fn set_error(&self, reason: CloseReason) -> Result<(), Error> {
+    let reason = self.status.set_close_reason(reason);
+    error!("Channel {} error: {}", self.id(), reason);
     self.set_state(ChannelState::Error);
-    self.connection.remove_channel(self.id)
+    self.connection.drop_channel_frames(self.id(), || reason.channel_closed());
+    self.fail_pending(|| reason.channel_closed());
+    let res = self.connection.remove_channel(self.id());
+    self.close_handler.on_close(&reason);
+    res
+  }
+
+  /// The whole connection got closed, `state` is either Closed or Error
+  pub(crate) fn set_connection_closed(&self, state: ChannelState, reason: CloseReason) {
+    let reason = self.status.set_close_reason(reason);
+    self.set_state(state);
+    self.fail_pending(|| reason.connection_closed());
+    self.close_handler.on_close(&reason);
+  }
+
+  /// Fail everything still waiting for the server on this channel
+  fn fail_pending<F: Fn() -> ErrorKind>(&self, error: F) {
+    self.connection.fail_expected_replies(self.id(), &error);
+    self.acknowledgements.fail_all_pending(&error);
+    for wait_handle in self.status.take_flow_waiters() {
+      wait_handle.error(error().into());
+    }
   }
 
   pub(crate) fn set_state(&self, state: ChannelState) {
@@ -75,25 +170,178 @@ impl Channel {
   }
 
   pub fn id(&self) -> u16 {
-    self.id
+    self.id.load(Ordering::SeqCst)
+  }
+
+  pub(crate) fn set_id(&self, id: u16) {
+    self.id.store(id, Ordering::SeqCst);
   }
 
   pub fn close(&self, reply_code: ShortUInt, reply_text: &str) -> Confirmation<()> {
     self.do_channel_close(reply_code, reply_text, 0, 0)
   }
 
-  pub fn basic_consume(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> Confirmation<Consumer> {
-    self.do_basic_consume(queue.borrow(), consumer_tag, options, arguments)
+  /// Declare an exchange of the given kind, see `ExchangeArguments` for its optional arguments
+  pub fn exchange_declare(&self, exchange: &str, kind: ExchangeKind, options: ExchangeDeclareOptions, arguments: FieldTable) -> Confirmation<()> {
+    self.do_exchange_declare(exchange, kind.kind(), options, arguments)
+  }
+
+  /// Start consuming from a queue, either a `Queue` or its name, declared on this channel or not
+  ///
+  /// Once all the clones of the returned `Consumer` are dropped, the deliveries which aren't
+  /// handled by a delegate are dropped rather than buffered, so cancel the consumer first.
+  pub fn basic_consume<Q: Borrow<str> + ?Sized>(&self, queue: &Q, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> Confirmation<Consumer> {
+    let spec = ConsumerSpec { options: options.clone(), arguments: arguments.clone() };
+    self.do_basic_consume(queue.borrow(), consumer_tag, options, arguments, spec)
+  }
+
+  /// Publish a message
+  ///
+  /// If the channel is in confirm mode, the returned Confirmation resolves once the server
+  /// acked or nacked the message, otherwise once the message has been sent.
+  ///
+  /// If too many frames are already waiting to be sent, the message is kept aside and the
+  /// returned Confirmation only resolves once there is room for it and it has been sent.
+  pub fn basic_publish(&self, exchange: &str, routing_key: &str, options: BasicPublishOptions, payload: impl Into<Bytes>, properties: BasicProperties) -> Confirmation<PublisherConfirm> {
+    self.do_basic_publish(exchange, routing_key, options, payload.into(), properties, true, None)
+  }
+
+  /// Publish a message, giving up on its confirm after `timeout`
+  ///
+  /// In confirm mode, the returned Confirmation fails with `ErrorKind::ConfirmTimeout` if the server
+  /// neither acked nor nacked the message in time, its confirm is then ignored if it comes later on.
+  /// Outside of confirm mode, this is the same as `basic_publish`.
+  pub fn basic_publish_with_timeout(&self, exchange: &str, routing_key: &str, options: BasicPublishOptions, payload: impl Into<Bytes>, properties: BasicProperties, timeout: Duration) -> Confirmation<PublisherConfirm> {
+    self.do_basic_publish(exchange, routing_key, options, payload.into(), properties, true, Some(timeout))
+  }
+
+  /// Publish a message, failing with `ErrorKind::OutboxFull` if too many frames are already waiting to be sent
+  pub fn try_basic_publish(&self, exchange: &str, routing_key: &str, options: BasicPublishOptions, payload: impl Into<Bytes>, properties: BasicProperties) -> Confirmation<PublisherConfirm> {
+    self.do_basic_publish(exchange, routing_key, options, payload.into(), properties, false, None)
   }
 
+  fn do_basic_publish(&self, exchange: &str, routing_key: &str, options: BasicPublishOptions, payload: Bytes, properties: BasicProperties, wait_for_room: bool, timeout: Option<Duration>) -> Confirmation<PublisherConfirm> {
+    if !self.status.is_connected() {
+      return Confirmation::new_error(ErrorKind::NotConnected.into());
+    }
+
+    let BasicPublishOptions { mandatory, immediate } = options;
+    let method = protocol::basic::Publish {
+      exchange: exchange.into(),
+      routing_key: routing_key.into(),
+      mandatory,
+      immediate,
+    };
+    let class_id = method.get_amqp_class_id();
+    let mut frames = vec![AMQPFrame::Method(self.id(), AMQPClass::Basic(protocol::basic::AMQPMethod::Publish(method))).into()];
+    frames.extend(self.content_frames(class_id, payload.clone(), properties));
+
+    // The server numbers the messages in the order it receives them, so the delivery tag
+    // has to be assigned in the same order as the frames get queued
+    let _publishing = self.publish_lock.lock();
+    if !wait_for_room && self.connection.outbox_full() {
+      return Confirmation::new_error(ErrorKind::OutboxFull.into());
+    }
+    let publisher_confirm = if self.status.confirm() {
+      let delivery_tag = self.delivery_tag.next();
+      Some((delivery_tag, self.acknowledgements.register_pending(delivery_tag)))
+    } else {
+      None
+    };
+    let send = match self.connection.send_frames(self.id(), Priority::LOW, frames, Some(payload)) {
+      Ok(send) => send,
+      Err(err) => return Confirmation::new_error(err),
+    };
+    if let Some((delivery_tag, publisher_confirm)) = publisher_confirm {
+      // If the channel dies before the message gets sent, we report that along with its payload
+      self.acknowledgements.sending(delivery_tag, send);
+      if let Some(timeout) = timeout {
+        let acknowledgements = self.acknowledgements.clone();
+        self.connection.schedule(timeout, Box::new(move || acknowledgements.timeout(delivery_tag)));
+      }
+      Confirmation::new(publisher_confirm)
+    } else {
+      Confirmation::new(send).map(Box::new(|_| PublisherConfirm::NotRequested))
+    }
+  }
+
+  /// Whether the server lets us publish on this channel, it can pause us with `channel.flow`
+  ///
+  /// While it doesn't, the published messages are kept aside, the other channels aren't affected.
+  pub fn flow(&self) -> bool {
+    self.status.flow()
+  }
+
+  /// Resolves once the server lets us publish on this channel again
+  pub fn wait_for_flow(&self) -> Confirmation<()> {
+    Confirmation::new(self.status.wait_for_flow())
+  }
+
+  /// Wait for the server to ack or nack all the messages published so far
+  ///
+  /// Resolves to the messages returned by the server in the meantime, unless they
+  /// were handed to the delegate set with `on_return`
   pub fn wait_for_confirms(&self) -> Confirmation<Vec<BasicReturnMessage>> {
-    if let Some(wait) = self.acknowledgements.get_last_pending() {
-      let returned_messages = self.returned_messages.clone();
-      Confirmation::new(wait).map(Box::new(move |_| returned_messages.drain()))
+    let returned_messages = self.returned_messages.clone();
+    Confirmation::new(self.acknowledgements.wait_for_pending()).map(Box::new(move |_| returned_messages.drain()))
+  }
+
+  /// Set the delegate receiving the messages the server returns to us, as they arrive
+  ///
+  /// Without a delegate, only the last returned messages are kept until `wait_for_confirms` drains them
+  pub fn on_return(&self, delegate: Box<dyn ReturnedMessageDelegate>) {
+    self.returned_messages.set_delegate(delegate);
+  }
+
+  /// Run `f` in a transaction: the channel gets in transaction mode if it isn't yet, and what `f`
+  /// did is committed if it succeeds, or rolled back if it fails or panics
+  ///
+  /// The channel stays in transaction mode afterwards, as AMQP can't take it out of it: everything
+  /// published on it outside of `transaction` only reaches the queues with the next commit. Use a
+  /// channel dedicated to transactions. Transactions can't be used on a channel in confirm mode.
+  pub fn transaction<T, F: FnOnce(&Channel) -> Result<T, Error>>(&self, f: F) -> Result<T, Error> {
+    self.tx_select_once().wait()?;
+    let guard = TransactionGuard::new(self.clone());
+    match f(self) {
+      Ok(value) => {
+        guard.commit().wait()?;
+        Ok(value)
+      },
+      Err(err)  => {
+        if let Err(rollback_err) = guard.rollback().wait() {
+          error!("Failed to roll back the transaction on channel {}: {}", self.id(), rollback_err);
+        }
+        Err(err)
+      },
+    }
+  }
+
+  /// Same as `transaction`, with `f` returning a future
+  #[cfg(feature = "futures")]
+  pub fn transaction_async<T, Fut: Future<Output = Result<T, Error>>, F: FnOnce(Channel) -> Fut>(&self, f: F) -> impl Future<Output = Result<T, Error>> {
+    TransactionFuture::new(self.clone(), self.tx_select_once(), f)
+  }
+
+  fn tx_select_once(&self) -> Confirmation<()> {
+    if self.status.confirm() {
+      Confirmation::new_error(ErrorKind::TransactionInConfirmMode.into())
+    } else if self.status.transaction() {
+      Confirmation::new_ok(())
     } else {
-      let (wait, wait_handle) = Wait::new();
-      wait_handle.finish(Vec::default());
-      Confirmation::new(wait)
+      self.tx_select()
+    }
+  }
+
+  pub(crate) fn diagnostics(&self) -> ChannelDiagnostics {
+    ChannelDiagnostics {
+      id:                         self.id(),
+      state:                      self.status.state(),
+      confirm:                    self.status.confirm(),
+      send_flow:                  self.status.flow(),
+      expected_replies:           self.connection.expected_replies(self.id()),
+      queues:                     self.queues.diagnostics(),
+      unconfirmed_delivery_tags:  self.acknowledgements.pending(),
+      buffered_returned_messages: self.returned_messages.buffered(),
     }
   }
 
@@ -103,28 +351,32 @@ impl Channel {
   }
 
   pub(crate) fn send_method_frame(&self, priority: Priority, method: AMQPClass, expected_reply: Option<Reply>) -> Result<Wait<()>, Error> {
-    self.send_frame(priority, AMQPFrame::Method(self.id, method), expected_reply)
+    self.send_frame(priority, AMQPFrame::Method(self.id(), method), expected_reply)
   }
 
   pub(crate) fn send_frame(&self, priority: Priority, frame: AMQPFrame, expected_reply: Option<Reply>) -> Result<Wait<()>, Error> {
-    self.connection.send_frame(self.id, priority, frame, expected_reply)
+    self.connection.send_frame(self.id(), priority, frame, expected_reply)
   }
 
-  fn send_content_frames(&self, class_id: u16, slice: &[u8], properties: BasicProperties) -> Result<Wait<()>, Error> {
+  fn content_frames(&self, class_id: u16, payload: Bytes, properties: BasicProperties) -> Vec<OutgoingFrame> {
     let header = AMQPContentHeader {
       class_id,
       weight:    0,
-      body_size: slice.len() as u64,
+      body_size: payload.len() as u64,
       properties,
     };
-    let mut wait = self.send_frame(Priority::LOW, AMQPFrame::Header(self.id, class_id, Box::new(header)), None)?;
+    let mut frames = vec![AMQPFrame::Header(self.id(), class_id, Box::new(header)).into()];
 
     let frame_max = self.connection.configuration().frame_max();
     //a content body frame 8 bytes of overhead
-    for chunk in slice.chunks(frame_max as usize - 8) {
-      wait = self.send_frame(Priority::LOW, AMQPFrame::Body(self.id, Vec::from(chunk)), None)?;
+    let chunk_size = frame_max as usize - 8;
+    let mut start  = 0;
+    while start < payload.len() {
+      let end = cmp::min(start + chunk_size, payload.len());
+      frames.push(OutgoingFrame::Body(self.id(), payload.slice(start..end)));
+      start = end;
     }
-    Ok(wait)
+    frames
   }
 
   pub(crate) fn handle_content_header_frame(&self, size: u64, properties: BasicProperties) -> Result<(), Error> {
@@ -135,16 +387,18 @@ impl Channel {
         self.status.set_state(ChannelState::Connected);
       }
       if let Some(queue_name) = queue_name {
-        self.queues.handle_content_header_frame(queue_name.as_str(), request_id_or_consumer_tag, size, properties);
+        if let Some(delivery_tag) = self.queues.handle_content_header_frame(queue_name.as_str(), request_id_or_consumer_tag, size, properties) {
+          self.requeue_abandoned_delivery(delivery_tag);
+        }
       } else {
         self.returned_messages.set_delivery_properties(properties);
         if size == 0 {
-          self.returned_messages.new_delivery_complete();
+          self.returned_message_complete();
         }
       }
       Ok(())
     } else {
-      self.set_error()
+      self.set_error(CloseReason::new(AMQPHardError::UNEXPECTEDFRAME.get_id(), "unexpected content header frame", 0, 0))
     }
   }
 
@@ -154,11 +408,13 @@ impl Channel {
     if let ChannelState::ReceivingContent(queue_name, request_id_or_consumer_tag, remaining_size) = self.status.state() {
       if remaining_size >= payload_size {
         if let Some(queue_name) = queue_name.as_ref() {
-          self.queues.handle_body_frame(queue_name.as_str(), request_id_or_consumer_tag.clone(), remaining_size, payload_size, payload);
+          if let Some(delivery_tag) = self.queues.handle_body_frame(queue_name.as_str(), request_id_or_consumer_tag.clone(), remaining_size, payload_size, payload) {
+            self.requeue_abandoned_delivery(delivery_tag);
+          }
         } else {
-          self.returned_messages.receive_delivery_content(payload);
+          self.returned_messages.receive_delivery_content(payload, remaining_size);
           if remaining_size == payload_size {
-            self.returned_messages.new_delivery_complete();
+            self.returned_message_complete();
           }
         }
         if remaining_size == payload_size {
@@ -169,10 +425,23 @@ impl Channel {
         Ok(())
       } else {
         error!("body frame too large");
-        self.set_error()
+        self.set_error(CloseReason::new(AMQPHardError::FRAMEERROR.get_id(), "body frame too large", 0, 0))
       }
     } else {
-        self.set_error()
+        self.set_error(CloseReason::new(AMQPHardError::UNEXPECTEDFRAME.get_id(), "unexpected content body frame", 0, 0))
+    }
+  }
+
+  /// Nobody can consume this delivery anymore, let the server hand it to another consumer
+  fn requeue_abandoned_delivery(&self, delivery_tag: DeliveryTag) {
+    if let Err(err) = self.basic_nack(delivery_tag, BasicNackOptions { multiple: false, requeue: true }).as_error() {
+      error!("Failed to requeue delivery {} on channel {}: {:?}", delivery_tag, self.id(), err);
+    }
+  }
+
+  fn returned_message_complete(&self) {
+    if let Some(message) = self.returned_messages.new_delivery_complete() {
+      self.acknowledgements.returned(message);
     }
   }
 
@@ -181,6 +450,83 @@ impl Channel {
     Err(error)
   }
 
+  /// Try to recover the channel from the io loop once the backoff delay elapsed
+  fn schedule_recovery(&self, reason: CloseReason) {
+    match self.recovery.next_attempt() {
+      Some(delay) => if self.connection.status().connected() {
+        let channel = self.clone();
+        self.connection.schedule(delay, Box::new(move || channel.try_recover(reason)));
+      } else {
+        self.recovery_failed(reason);
+      },
+      None        => self.recovery_failed(reason),
+    }
+  }
+
+  /// Open the channel again and restore its state, each step being started from the reply to the previous one
+  fn try_recover(&self, reason: CloseReason) {
+    if !self.connection.status().connected() {
+      return self.recovery_failed(reason);
+    }
+    self.status.reset();
+    self.delivery_tag.reset();
+    // The server requeued everything we didn't ack, and the delivery tags aren't valid anymore
+    self.queues.drop_prefetched_messages();
+    if let Err(err) = self.connection.reopen_channel(self) {
+      error!("Failed to reopen channel: {}", err);
+      return self.schedule_recovery(reason);
+    }
+    info!("Recovering channel with id {}", self.id());
+    let mut steps = VecDeque::<Step>::new();
+    let channel   = self.clone();
+    steps.push_back(Box::new(move || channel.channel_open().ignore_value()));
+    if self.recovery.confirm() {
+      let channel = self.clone();
+      steps.push_back(Box::new(move || channel.confirm_select(ConfirmSelectOptions::default())));
+    }
+    if let Some((prefetch_count, options)) = self.recovery.qos() {
+      let channel = self.clone();
+      steps.push_back(Box::new(move || channel.basic_qos(prefetch_count, options)));
+    }
+    for (consumer_tag, queue, spec) in self.recovery.consumers() {
+      let channel = self.clone();
+      steps.push_back(Box::new(move || channel.do_basic_consume(queue.as_str(), consumer_tag.as_str(), spec.options.clone(), spec.arguments.clone(), spec).ignore_value()));
+    }
+    let channel = self.clone();
+    sequence(steps, move |res| match res {
+      Ok(())   => {
+        channel.recovery.stop();
+        info!("Recovered channel with id {}", channel.id());
+        channel.close_handler.on_recovery();
+      },
+      Err(err) => channel.recovery_step_failed(err),
+    });
+  }
+
+  fn recovery_step_failed(&self, err: Error) {
+    if let ErrorKind::ChannelClosed(reason) = err.kind() {
+      // The server closed the channel again, try again later
+      if self.status.state() == ChannelState::Closed {
+        return self.schedule_recovery(reason.clone());
+      }
+    }
+    error!("Failed to recover channel {}: {}", self.id(), err);
+    let reason = CloseReason::from(&err);
+    self.recovery.stop();
+    self.close_handler.on_close(&reason);
+    if self.status.is_connected() {
+      if let Err(err) = self.do_channel_close(reason.reply_code, reason.reply_text.as_str(), 0, 0).as_error() {
+        error!("Failed to close channel {}: {}", self.id(), err);
+      }
+    }
+  }
+
+  fn recovery_failed(&self, reason: CloseReason) {
+    self.recovery.stop();
+    error!("Giving up recovering channel {}, closed by: {}", self.id(), reason);
+    self.close_handler.on_close(&reason);
+  }
+
   fn on_connection_start_ok_sent(&self, wait_handle: WaitHandle<Connection>, credentials: Credentials) -> Result<(), Error> {
     self.connection.set_state(ConnectionState::SentStartOk(wait_handle, credentials));
     Ok(())
@@ -191,16 +537,18 @@ impl Channel {
     Ok(())
   }
 
-  fn on_connection_close_sent(&self) -> Result<(), Error> {
+  fn on_connection_close_sent(&self, reply_code: ShortUInt, reply_text: &str, class_id: ShortUInt, method_id: ShortUInt) -> Result<(), Error> {
+    self.connection.status().set_close_reason(CloseReason::new(reply_code, reply_text, class_id, method_id));
     self.connection.set_closing();
     Ok(())
   }
 
   fn on_connection_close_ok_sent(&self) -> Result<(), Error> {
-    self.connection.set_closed()
+    self.connection.set_closed_by_server()
   }
 
-  fn on_channel_close_sent(&self) -> Result<(), Error> {
+  fn on_channel_close_sent(&self, reply_code: ShortUInt, reply_text: &str, class_id: ShortUInt, method_id: ShortUInt) -> Result<(), Error> {
+    self.status.set_close_reason(CloseReason::new(reply_code, reply_text, class_id, method_id));
     self.set_closing();
     Ok(())
   }
@@ -209,13 +557,9 @@ impl Channel {
     self.set_closed()
   }
 
-  fn on_basic_publish_sent(&self, class_id: u16, payload: Vec<u8>, properties: BasicProperties) -> Result<Wait<()>, Error> {
-    if self.status.confirm() {
-      let delivery_tag = self.delivery_tag.next();
-      self.acknowledgements.register_pending(delivery_tag);
-    };
-
-    self.send_content_frames(class_id, payload.as_slice(), properties)
+  fn on_basic_qos_sent(&self, prefetch_count: ShortUInt, global: Boolean) -> Result<(), Error> {
+    self.recovery.set_qos(prefetch_count, BasicQosOptions { global });
+    Ok(())
   }
 
   fn on_basic_recover_async_sent(&self) -> Result<(), Error> {
@@ -301,8 +645,9 @@ impl Channel {
       self.connection_start_ok(options.client_properties, &mechanism, &credentials.sasl_auth_string(options.mechanism), &locale, wait_handle, credentials).as_error()
     } else {
       error!("Invalid state: {:?}", state);
-      self.connection.set_error()?;
-      Err(ErrorKind::InvalidConnectionState(state).into())
+      let error: Error = ErrorKind::InvalidConnectionState(state).into();
+      self.connection.set_error(CloseReason::from(&error))?;
+      Err(error)
     }
   }
 
@@ -314,8 +659,9 @@ impl Channel {
       self.connection_secure_ok(&credentials.rabbit_cr_demo_answer()).as_error()
     } else {
       error!("Invalid state: {:?}", state);
-      self.connection.set_error()?;
-      Err(ErrorKind::InvalidConnectionState(state).into())
+      let error: Error = ErrorKind::InvalidConnectionState(state).into();
+      self.connection.set_error(CloseReason::from(&error))?;
+      Err(error)
     }
   }
 
@@ -330,8 +676,9 @@ impl Channel {
       self.connection_open(&self.connection.status().vhost(), wait_handle).as_error()
     } else {
       error!("Invalid state: {:?}", state);
-      self.connection.set_error()?;
-      Err(ErrorKind::InvalidConnectionState(state).into())
+      let error: Error = ErrorKind::InvalidConnectionState(state).into();
+      self.connection.set_error(CloseReason::from(&error))?;
+      Err(error)
     }
   }
 
@@ -343,20 +690,22 @@ impl Channel {
       Ok(())
     } else {
       error!("Invalid state: {:?}", state);
-      self.connection.set_error()?;
-      Err(ErrorKind::InvalidConnectionState(state).into())
+      let error: Error = ErrorKind::InvalidConnectionState(state).into();
+      self.connection.set_error(CloseReason::from(&error))?;
+      Err(error)
     }
   }
 
   fn on_connection_close_received(&self, method: protocol::connection::Close) -> Result<(), Error> {
     if let Some(error) = AMQPError::from_id(method.reply_code) {
-      error!("Connection closed on channel {} by {}:{} => {:?} => {}", self.id, method.class_id, method.method_id, error, method.reply_text);
+      error!("Connection closed on channel {} by {}:{} => {:?} => {}", self.id(), method.class_id, method.method_id, error, method.reply_text);
     } else {
-      info!("Connection closed on channel {}: {:?}", self.id, method);
+      info!("Connection closed on channel {}: {:?}", self.id(), method);
     }
-    let state = self.connection.status().state();
+    let state  = self.connection.status().state();
+    let reason = self.connection.status().set_close_reason(CloseReason::new(method.reply_code, method.reply_text.as_str(), method.class_id, method.method_id));
     self.connection.set_closing();
-    self.connection.drop_pending_frames();
+    self.connection.drop_pending_frames(&reason);
     self.connection_close_ok().as_error()?;
     match state {
       ConnectionState::SentProtocolHeader(wait_handle, ..) => wait_handle.error(ErrorKind::ConnectionRefused.into()),
@@ -388,8 +737,14 @@ impl Channel {
   }
 
   fn on_channel_flow_received(&self, method: protocol::channel::Flow) -> Result<(), Error> {
-    self.status.set_send_flow(method.active);
-    self.channel_flow_ok(ChannelFlowOkOptions {active: method.active}).as_error()
+    let flow_waiters = self.status.set_send_flow(method.active);
+    let res          = self.channel_flow_ok(ChannelFlowOkOptions {active: method.active}).as_error();
+    for wait_handle in flow_waiters {
+      wait_handle.finish(());
+    }
+    // Only let the io loop send the parked frames once the reply is queued and the waiters know about it
+    self.connection.set_channel_flow(self.id(), method.active)?;
+    res
   }
 
   fn on_channel_flow_ok_received(&self, method: protocol::channel::FlowOk, wait_handle: WaitHandle<Boolean>) -> Result<(), Error> {
@@ -400,11 +755,24 @@ impl Channel {
 
   fn on_channel_close_received(&self, method: protocol::channel::Close) -> Result<(), Error> {
     if let Some(error) = AMQPError::from_id(method.reply_code) {
-      error!("Channel {} closed by {}:{} => {:?} => {}", self.id, method.class_id, method.method_id, error, method.reply_text);
+      error!("Channel {} closed by {}:{} => {:?} => {}", self.id(), method.class_id, method.method_id, error, method.reply_text);
     } else {
-      info!("Channel {} closed: {:?}", self.id, method);
+      info!("Channel {} closed: {:?}", self.id(), method);
+    }
+    let reason = self.status.set_close_reason(CloseReason::new(method.reply_code, method.reply_text.as_str(), method.class_id, method.method_id));
+    self.set_closing();
+    // Nothing we didn't send yet will be handled by the server anymore
+    self.connection.drop_channel_frames(self.id(), || reason.channel_closed());
+    // Start recovering before the close-ok marks the channel as closed, so that its handlers wait for the outcome
+    let recover = self.recovery.is_enabled() && !self.recovery.is_recovering() && self.connection.status().connected();
+    if recover {
+      self.recovery.start(self.status.confirm());
+    }
+    self.channel_close_ok().as_error()?;
+    if recover {
+      self.schedule_recovery(reason);
     }
-    self.channel_close_ok().as_error()
+    Ok(())
   }
 
   fn on_channel_close_ok_received(&self) -> Result<(), Error> {
@@ -436,23 +804,26 @@ impl Channel {
   }
 
   fn on_basic_get_empty_received(&self, _: protocol::basic::GetEmpty) -> Result<(), Error> {
-    match self.connection.next_expected_reply(self.id) {
+    match self.connection.next_expected_reply(self.id()) {
       Some(Reply::AwaitingBasicGetOk(wait_handle, _)) => {
         wait_handle.finish(None);
         Ok(())
       },
       _ => {
-        self.set_error()?;
-        Err(ErrorKind::UnexpectedReply.into())
+        let error: Error = ErrorKind::UnexpectedReply.into();
+        self.set_error(CloseReason::from(&error))?;
+        Err(error)
       }
     }
   }
 
   #[allow(clippy::too_many_arguments)]
-  fn on_basic_consume_ok_received(&self, method: protocol::basic::ConsumeOk, wait_handle: WaitHandle<Consumer>, queue: ShortString) -> Result<(), Error> {
-    let consumer = Consumer::new(method.consumer_tag.clone());
+  fn on_basic_consume_ok_received(&self, method: protocol::basic::ConsumeOk, wait_handle: WaitHandle<Consumer>, queue: ShortString, spec: ConsumerSpec) -> Result<(), Error> {
+    // Keep the consumer we already handed out when consuming again on a recovered channel
+    let consumer = self.queues.get_consumer(method.consumer_tag.as_str()).unwrap_or_else(|| Consumer::new(method.consumer_tag.clone(), spec.options.no_ack, self.connection.delivery_budget()));
+    self.recovery.register_consumer(method.consumer_tag.clone(), queue.clone(), spec);
     self.queues.register_consumer(queue.as_str(), method.consumer_tag, consumer.clone());
-    wait_handle.finish(consumer);
+    wait_handle.finish(consumer.user_handle());
     Ok(())
   }
 
@@ -464,6 +835,7 @@ impl Channel {
   }
 
   fn on_basic_cancel_received(&self, method: protocol::basic::Cancel) -> Result<(), Error> {
+    self.recovery.deregister_consumer(method.consumer_tag.as_str());
     self.queues.deregister_consumer(method.consumer_tag.as_str());
     if !method.nowait {
       self.basic_cancel_ok(method.consumer_tag.as_str()).as_error()
@@ -473,6 +845,7 @@ impl Channel {
   }
 
   fn on_basic_cancel_ok_received(&self, method: protocol::basic::CancelOk) -> Result<(), Error> {
+    self.recovery.deregister_consumer(method.consumer_tag.as_str());
     self.queues.deregister_consumer(method.consumer_tag.as_str());
     Ok(())
   }
@@ -523,6 +896,11 @@ impl Channel {
     Ok(())
   }
 
+  fn on_tx_select_ok_received(&self) -> Result<(), Error> {
+    self.status.set_transaction();
+    Ok(())
+  }
+
   fn on_access_request_ok_received(&self, _: protocol::access::RequestOk) -> Result<(), Error> {
     Ok(())
   }
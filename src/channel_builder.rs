@@ -0,0 +1,241 @@
+use log::error;
+
+use std::{
+  collections::VecDeque,
+  fmt,
+};
+
+use crate::{
+  Channel, CloseReason, Connection,
+  close_reason::REPLY_SUCCESS,
+  confirmation::{Confirmation, Step, sequence},
+  options::{BasicQosOptions, ChannelFlowOptions, ConfirmSelectOptions},
+  returned_messages::ReturnedMessageDelegate,
+  types::ShortUInt,
+  wait::{Wait, WaitHandle},
+};
+
+type Handler = Box<dyn Fn(CloseReason) + Send + 'static>;
+
+/// Open a channel with its confirm mode, qos, flow and handlers already set up
+///
+/// Each step is sent once the previous one succeeded. If one of them fails, the channel gets closed
+/// and the first error is returned, so that we never hand out a half configured channel.
+pub struct ChannelBuilder {
+  connection:     Connection,
+  confirm_select: Option<ConfirmSelectOptions>,
+  basic_qos:      Option<(ShortUInt, BasicQosOptions)>,
+  channel_flow:   Option<ChannelFlowOptions>,
+  recovery:       bool,
+  on_return:      Option<Box<dyn ReturnedMessageDelegate>>,
+  on_close:       Option<Handler>,
+  on_error:       Option<Handler>,
+}
+
+impl ChannelBuilder {
+  pub(crate) fn new(connection: Connection) -> Self {
+    Self {
+      connection,
+      confirm_select: None,
+      basic_qos:      None,
+      channel_flow:   None,
+      recovery:       false,
+      on_return:      None,
+      on_close:       None,
+      on_error:       None,
+    }
+  }
+
+  /// Put the channel in confirm mode
+  pub fn confirm_select(mut self, options: ConfirmSelectOptions) -> Self {
+    self.confirm_select = Some(options);
+    self
+  }
+
+  /// Set the prefetch of the channel
+  pub fn basic_qos(mut self, prefetch_count: ShortUInt, options: BasicQosOptions) -> Self {
+    self.basic_qos = Some((prefetch_count, options));
+    self
+  }
+
+  /// Ask the server to pause or resume the deliveries on the channel
+  pub fn channel_flow(mut self, options: ChannelFlowOptions) -> Self {
+    self.channel_flow = Some(options);
+    self
+  }
+
+  /// See `Channel::enable_recovery`
+  pub fn enable_recovery(mut self) -> Self {
+    self.recovery = true;
+    self
+  }
+
+  /// See `Channel::on_return`
+  pub fn on_return(mut self, delegate: Box<dyn ReturnedMessageDelegate>) -> Self {
+    self.on_return = Some(delegate);
+    self
+  }
+
+  /// See `Channel::on_close`
+  pub fn on_close<C: Fn(CloseReason) + Send + 'static>(mut self, handler: Box<C>) -> Self {
+    self.on_close = Some(handler);
+    self
+  }
+
+  /// See `Channel::on_error`
+  pub fn on_error<E: Fn(CloseReason) + Send + 'static>(mut self, handler: Box<E>) -> Self {
+    self.on_error = Some(handler);
+    self
+  }
+
+  /// Open and configure the channel
+  ///
+  /// Nothing blocks: every step is sent from the io loop, when it gets the reply to the previous one.
+  pub fn build(self) -> Confirmation<Channel> {
+    let (wait, wait_handle) = Wait::new();
+    self.connection.create_channel().then(move |res| match res {
+      Ok(channel) => self.configure(channel, wait_handle),
+      Err(err)    => wait_handle.error(err),
+    });
+    Confirmation::new(wait)
+  }
+
+  fn configure(self, channel: Channel, wait_handle: WaitHandle<Channel>) {
+    let mut steps = VecDeque::<Step>::new();
+    if let Some(options) = self.confirm_select.clone() {
+      let channel = channel.clone();
+      steps.push_back(Box::new(move || channel.confirm_select(options)));
+    }
+    if let Some((prefetch_count, options)) = self.basic_qos.clone() {
+      let channel = channel.clone();
+      steps.push_back(Box::new(move || channel.basic_qos(prefetch_count, options)));
+    }
+    if let Some(options) = self.channel_flow.clone() {
+      let channel = channel.clone();
+      steps.push_back(Box::new(move || channel.channel_flow(options).ignore_value()));
+    }
+    sequence(steps, move |res| match res {
+      Ok(())   => {
+        self.register(&channel);
+        wait_handle.finish(channel);
+      },
+      Err(err) => if channel.status().is_connected() {
+        let id = channel.id();
+        channel.close(REPLY_SUCCESS, "failed to configure the channel").then(move |res| {
+          if let Err(close_err) = res {
+            error!("Failed to close channel {} after a configuration error: {}", id, close_err);
+          }
+          wait_handle.error(err);
+        });
+      } else {
+        wait_handle.error(err);
+      },
+    });
+  }
+
+  /// The handlers are only registered once the channel is ready, closing it on error doesn't notify them
+  fn register(self, channel: &Channel) {
+    if self.recovery {
+      channel.enable_recovery();
+    }
+    if let Some(delegate) = self.on_return {
+      channel.on_return(delegate);
+    }
+    if let Some(handler) = self.on_close {
+      channel.on_close(Box::new(handler));
+    }
+    if let Some(handler) = self.on_error {
+      channel.on_error(Box::new(handler));
+    }
+  }
+}
+
+impl fmt::Debug for ChannelBuilder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ChannelBuilder")
+      .field("confirm_select", &self.confirm_select)
+      .field("basic_qos", &self.basic_qos)
+      .field("channel_flow", &self.channel_flow)
+      .field("recovery", &self.recovery)
+      .field("has_on_return", &self.on_return.is_some())
+      .field("has_on_close", &self.on_close.is_some())
+      .field("has_on_error", &self.on_error.is_some())
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use amq_protocol::{
+    frame::AMQPFrame,
+    protocol::{AMQPClass, basic, channel, confirm},
+  };
+
+  use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+  use crate::{
+    connection_status::ConnectionState,
+    error::ErrorKind,
+  };
+
+  fn connection() -> Connection {
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration().set_channel_max(2047);
+    conn
+  }
+
+  fn counter(count: &Arc<AtomicUsize>) -> Box<impl Fn(CloseReason) + Send + 'static> {
+    let count = count.clone();
+    Box::new(move |_| { count.fetch_add(1, Ordering::SeqCst); })
+  }
+
+  #[test]
+  fn steps_are_sent_once_the_previous_one_succeeded() {
+    let _ = env_logger::try_init();
+
+    let conn    = connection();
+    let closed  = Arc::new(AtomicUsize::new(0));
+    let builder = conn.channel_builder().confirm_select(ConfirmSelectOptions::default()).basic_qos(10, BasicQosOptions::default()).on_close(counter(&closed)).build();
+    assert_eq!(conn.expected_replies(1), vec!["channel.open-ok"]);
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())))).unwrap();
+    assert_eq!(conn.expected_replies(1), vec!["confirm.select-ok"]);
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})))).unwrap();
+    assert_eq!(conn.expected_replies(1), vec!["basic.qos-ok"]);
+    assert!(builder.try_wait().is_none());
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})))).unwrap();
+    let channel = builder.try_wait().unwrap().unwrap();
+    assert!(channel.status().is_connected());
+    assert!(channel.status().confirm());
+    channel.close(REPLY_SUCCESS, "done");
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})))).unwrap();
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn channel_is_closed_when_a_step_fails() {
+    let _ = env_logger::try_init();
+
+    let conn    = connection();
+    let closed  = Arc::new(AtomicUsize::new(0));
+    let builder = conn.channel_builder().confirm_select(ConfirmSelectOptions::default()).basic_qos(10, BasicQosOptions::default()).on_close(counter(&closed)).build();
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())))).unwrap();
+    conn.fail_expected_replies(1, || ErrorKind::NotConnected);
+    // The error is only returned once the channel got closed
+    assert_eq!(conn.expected_replies(1), vec!["channel.close-ok"]);
+    assert!(builder.try_wait().is_none());
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})))).unwrap();
+    match builder.try_wait() {
+      Some(Err(err)) => match err.kind() {
+        ErrorKind::NotConnected => {},
+        kind                    => panic!("unexpected error: {:?}", kind),
+      },
+      res            => panic!("unexpected result: {:?}", res.map(|res| res.is_ok())),
+    }
+    assert!(conn.expected_replies(1).is_empty());
+    // The handlers were never registered on the half configured channel
+    assert_eq!(closed.load(Ordering::SeqCst), 0);
+  }
+}
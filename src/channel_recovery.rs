@@ -0,0 +1,160 @@
+use parking_lot::Mutex;
+
+use std::{
+  cmp,
+  collections::HashMap,
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::{
+  options::{BasicConsumeOptions, BasicQosOptions},
+  types::{FieldTable, ShortString, ShortUInt},
+};
+
+/// What was set up on a channel, to restore it once the server closed it and we opened it again
+/// How many times we try to open the channel again before giving up
+const MAX_ATTEMPTS: u32 = 6;
+/// The delay before the first attempt, doubled after each failure
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChannelRecovery {
+  inner: Arc<Mutex<Inner>>,
+}
+
+/// How a consumer was started, to start it again on the recovered channel
+#[derive(Clone, Debug)]
+pub(crate) struct ConsumerSpec {
+  pub(crate) options:   BasicConsumeOptions,
+  pub(crate) arguments: FieldTable,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  enabled:    bool,
+  recovering: bool,
+  attempts:   u32,
+  /// Whether confirm mode was enabled on the channel before the server closed it
+  confirm:    bool,
+  qos:        Option<(ShortUInt, BasicQosOptions)>,
+  /// The queue and the spec of each consumer, by consumer tag
+  consumers:  HashMap<ShortString, (ShortString, ConsumerSpec)>,
+}
+
+impl ChannelRecovery {
+  pub(crate) fn enable(&self) {
+    self.inner.lock().enabled = true;
+  }
+
+  pub(crate) fn is_enabled(&self) -> bool {
+    self.inner.lock().enabled
+  }
+
+  pub(crate) fn start(&self, confirm: bool) {
+    let mut inner = self.inner.lock();
+    inner.recovering = true;
+    inner.attempts   = 0;
+    inner.confirm    = confirm;
+  }
+
+  pub(crate) fn is_recovering(&self) -> bool {
+    self.inner.lock().recovering
+  }
+
+  pub(crate) fn confirm(&self) -> bool {
+    self.inner.lock().confirm
+  }
+
+  /// The delay before the next attempt, or None once we ran out of attempts
+  pub(crate) fn next_attempt(&self) -> Option<Duration> {
+    let mut inner = self.inner.lock();
+    if !inner.recovering || inner.attempts >= MAX_ATTEMPTS {
+      return None;
+    }
+    let delay = cmp::min(INITIAL_DELAY * 2u32.pow(inner.attempts), MAX_DELAY);
+    inner.attempts += 1;
+    Some(delay)
+  }
+
+  pub(crate) fn stop(&self) {
+    self.inner.lock().recovering = false;
+  }
+
+  pub(crate) fn set_qos(&self, prefetch_count: ShortUInt, options: BasicQosOptions) {
+    self.inner.lock().qos = Some((prefetch_count, options));
+  }
+
+  pub(crate) fn qos(&self) -> Option<(ShortUInt, BasicQosOptions)> {
+    self.inner.lock().qos.clone()
+  }
+
+  pub(crate) fn register_consumer(&self, consumer_tag: ShortString, queue: ShortString, spec: ConsumerSpec) {
+    self.inner.lock().consumers.insert(consumer_tag, (queue, spec));
+  }
+
+  pub(crate) fn deregister_consumer(&self, consumer_tag: &str) {
+    self.inner.lock().consumers.remove(consumer_tag);
+  }
+
+  pub(crate) fn consumers(&self) -> Vec<(ShortString, ShortString, ConsumerSpec)> {
+    self.inner.lock().consumers.iter().map(|(consumer_tag, (queue, spec))| (consumer_tag.clone(), queue.clone(), spec.clone())).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn spec() -> ConsumerSpec {
+    ConsumerSpec {
+      options:   BasicConsumeOptions::default(),
+      arguments: FieldTable::default(),
+    }
+  }
+
+  #[test]
+  fn canceled_consumers_are_not_recovered() {
+    let recovery = ChannelRecovery::default();
+    recovery.register_consumer("consumer-1".into(), "queue".into(), spec());
+    recovery.register_consumer("consumer-2".into(), "queue".into(), spec());
+    recovery.deregister_consumer("consumer-1");
+    let consumers = recovery.consumers();
+    assert_eq!(consumers.len(), 1);
+    assert_eq!(consumers[0].0.as_str(), "consumer-2");
+    assert_eq!(consumers[0].1.as_str(), "queue");
+  }
+
+  #[test]
+  fn attempts_back_off_until_giving_up() {
+    let recovery = ChannelRecovery::default();
+    assert_eq!(recovery.next_attempt(), None);
+    recovery.start(true);
+    assert!(recovery.is_recovering());
+    assert!(recovery.confirm());
+    let delays: Vec<Duration> = std::iter::from_fn(|| recovery.next_attempt()).collect();
+    assert_eq!(delays, vec![
+      Duration::from_millis(100),
+      Duration::from_millis(200),
+      Duration::from_millis(400),
+      Duration::from_millis(800),
+      Duration::from_millis(1600),
+      Duration::from_millis(3200),
+    ]);
+    recovery.start(false);
+    assert_eq!(recovery.next_attempt(), Some(Duration::from_millis(100)));
+    recovery.stop();
+    assert!(!recovery.is_recovering());
+    assert_eq!(recovery.next_attempt(), None);
+  }
+
+  #[test]
+  fn last_qos_is_recovered() {
+    let recovery = ChannelRecovery::default();
+    assert!(recovery.qos().is_none());
+    recovery.set_qos(10, BasicQosOptions::default());
+    recovery.set_qos(20, BasicQosOptions { global: true });
+    assert_eq!(recovery.qos(), Some((20, BasicQosOptions { global: true })));
+  }
+}
@@ -2,7 +2,11 @@ use parking_lot::RwLock;
 
 use std::sync::Arc;
 
-use crate::types::ShortString;
+use crate::{
+  close_reason::CloseReason,
+  types::ShortString,
+  wait::{Wait, WaitHandle},
+};
 
 #[derive(Clone, Debug, Default)]
 pub struct ChannelStatus {
@@ -30,6 +34,15 @@ impl ChannelStatus {
     self.inner.write().confirm = true
   }
 
+  /// Whether the channel is in transaction mode
+  pub fn transaction(&self) -> bool {
+    self.inner.read().transaction
+  }
+
+  pub(crate) fn set_transaction(&self) {
+    self.inner.write().transaction = true
+  }
+
   pub fn state(&self) -> ChannelState {
     self.inner.read().state.clone()
   }
@@ -38,16 +51,54 @@ impl ChannelStatus {
     self.inner.write().state = state
   }
 
-  pub(crate) fn set_send_flow(&self, flow: bool) {
-    self.inner.write().send_flow = flow;
+  /// Returns what was waiting for the flow to be active again, if it now is
+  pub(crate) fn set_send_flow(&self, flow: bool) -> Vec<WaitHandle<()>> {
+    let mut inner = self.inner.write();
+    inner.send_flow = flow;
+    if flow {
+      inner.flow_waiters.drain(..).collect()
+    } else {
+      Vec::new()
+    }
   }
 
-  pub(crate) fn flow(&self) -> bool {
+  /// Whether the server lets us publish on the channel
+  pub fn flow(&self) -> bool {
     self.inner.read().send_flow
   }
+
+  pub(crate) fn wait_for_flow(&self) -> Wait<()> {
+    let (wait, wait_handle) = Wait::new();
+    let mut inner = self.inner.write();
+    if inner.send_flow {
+      wait_handle.finish(());
+    } else {
+      inner.flow_waiters.push(wait_handle);
+    }
+    wait
+  }
+
+  pub(crate) fn take_flow_waiters(&self) -> Vec<WaitHandle<()>> {
+    self.inner.write().flow_waiters.drain(..).collect()
+  }
+
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.inner.read().close_reason.clone()
+  }
+
+  /// Only the first reason is kept, as it's the one which led to the other ones, and returned
+  pub(crate) fn set_close_reason(&self, reason: CloseReason) -> CloseReason {
+    self.inner.write().close_reason.get_or_insert(reason).clone()
+  }
+
+  /// Forget everything about the previous channel when opening it again
+  pub(crate) fn reset(&self) {
+    *self.inner.write() = Inner::default();
+  }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ChannelState {
     Initial,
     Connected,
@@ -67,17 +118,23 @@ impl Default for ChannelState {
 
 #[derive(Debug)]
 struct Inner {
-  confirm:   bool,
-  send_flow: bool,
-  state:     ChannelState,
+  confirm:      bool,
+  transaction:  bool,
+  send_flow:    bool,
+  flow_waiters: Vec<WaitHandle<()>>,
+  state:        ChannelState,
+  close_reason: Option<CloseReason>,
 }
 
 impl Default for Inner {
   fn default() -> Self {
     Self {
-      confirm:   false,
-      send_flow: true,
-      state:     ChannelState::default(),
+      confirm:      false,
+      transaction:  false,
+      send_flow:    true,
+      flow_waiters: Vec::new(),
+      state:        ChannelState::default(),
+      close_reason: None,
     }
   }
 }
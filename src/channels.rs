@@ -9,7 +9,9 @@ use std::{
 
 use crate::{
   BasicProperties, Channel, ChannelState, Error, ErrorKind,
+  close_reason::CloseReason,
   connection::Connection,
+  diagnostics::ChannelDiagnostics,
   id_sequence::IdSequence,
 };
 
@@ -23,6 +25,11 @@ impl Channels {
     self.inner.lock().create(connection)
   }
 
+  /// Register a channel the server closed again, under a new id
+  pub(crate) fn reopen(&self, channel: &Channel, channel_max: u16) -> Result<(), Error> {
+    self.inner.lock().reopen(channel, channel_max)
+  }
+
   pub(crate) fn create_zero(&self, connection: Connection) {
     self.inner.lock().create_channel(0, connection).set_state(ChannelState::Connected);
   }
@@ -69,22 +76,27 @@ impl Channels {
     }
   }
 
-  pub(crate) fn set_closed(&self) -> Result<(), Error> {
-    for (_, channel) in self.inner.lock().channels.drain() {
-      channel.set_state(ChannelState::Closed);
+  pub(crate) fn set_closed(&self, reason: CloseReason) -> Result<(), Error> {
+    let channels = self.inner.lock().channels.drain().collect::<Vec<_>>();
+    for (_, channel) in channels {
+      channel.set_connection_closed(ChannelState::Closed, reason.clone());
     }
     Ok(())
   }
 
-  pub(crate) fn set_error(&self) -> Result<(), Error> {
-    for channel in self.inner.lock().channels.values() {
-      channel.set_error()?;
+  pub(crate) fn set_error(&self, reason: CloseReason) -> Result<(), Error> {
+    // Don't hold the lock while failing what's pending, so that the callbacks can use the channels
+    let channels = self.inner.lock().channels.values().cloned().collect::<Vec<_>>();
+    for channel in channels {
+      channel.set_connection_closed(ChannelState::Error, reason.clone());
     }
     Ok(())
   }
 
-  pub(crate) fn flow(&self) -> bool {
-    self.inner.lock().channels.values().all(|c| c.status().flow())
+  pub(crate) fn diagnostics(&self) -> Vec<ChannelDiagnostics> {
+    let mut channels = self.inner.lock().channels.values().cloned().collect::<Vec<_>>();
+    channels.sort_by_key(Channel::id);
+    channels.iter().map(Channel::diagnostics).collect()
   }
 }
 
@@ -113,7 +125,20 @@ impl Inner {
 
   fn create(&mut self, connection: Connection) -> Result<Channel, Error> {
     debug!("create channel");
-    self.channel_id.set_max(connection.configuration().channel_max());
+    let id = self.free_id(connection.configuration().channel_max())?;
+    Ok(self.create_channel(id, connection))
+  }
+
+  fn reopen(&mut self, channel: &Channel, channel_max: u16) -> Result<(), Error> {
+    let id = self.free_id(channel_max)?;
+    debug!("reopen channel {} with id {}", channel.id(), id);
+    channel.set_id(id);
+    self.channels.insert(id, channel.clone());
+    Ok(())
+  }
+
+  fn free_id(&mut self, channel_max: u16) -> Result<u16, Error> {
+    self.channel_id.set_max(channel_max);
     let first_id = self.channel_id.next();
     let mut looped = false;
     let mut id = first_id;
@@ -122,7 +147,7 @@ impl Inner {
         looped = true;
       }
       if !self.channels.contains_key(&id) {
-        return Ok(self.create_channel(id, connection))
+        return Ok(id)
       }
       id = self.channel_id.next();
     }
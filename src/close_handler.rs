@@ -0,0 +1,159 @@
+use parking_lot::Mutex;
+
+use std::{
+  fmt,
+  sync::Arc,
+};
+
+use crate::close_reason::CloseReason;
+
+type Handler         = Box<dyn Fn(CloseReason) + Send + 'static>;
+type RecoveryHandler = Box<dyn Fn() + Send + 'static>;
+
+#[derive(Clone, Default)]
+pub(crate) struct CloseHandler {
+  inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+  on_close:    Option<Handler>,
+  on_error:    Option<Handler>,
+  on_recovery: Option<RecoveryHandler>,
+  /// Why the channel got closed, once it has been notified
+  reason:      Option<CloseReason>,
+}
+
+impl CloseHandler {
+  /// If the channel is already closed, the handler is called right away
+  pub(crate) fn set_close_handler<C: Fn(CloseReason) + Send + 'static>(&self, handler: Box<C>) {
+    let reason = {
+      let mut inner = self.inner.lock();
+      match inner.reason.clone() {
+        Some(reason) => reason,
+        None         => {
+          inner.on_close = Some(handler);
+          return;
+        },
+      }
+    };
+    handler(reason);
+    self.inner.lock().on_close.get_or_insert(handler);
+  }
+
+  /// If the channel is already closed because of an error, the handler is called right away
+  pub(crate) fn set_error_handler<E: Fn(CloseReason) + Send + 'static>(&self, handler: Box<E>) {
+    let reason = {
+      let mut inner = self.inner.lock();
+      match inner.reason.clone() {
+        Some(reason) => reason,
+        None         => {
+          inner.on_error = Some(handler);
+          return;
+        },
+      }
+    };
+    if reason.is_error() {
+      handler(reason);
+    }
+    self.inner.lock().on_error.get_or_insert(handler);
+  }
+
+  pub(crate) fn set_recovery_handler<R: Fn() + Send + 'static>(&self, handler: Box<R>) {
+    self.inner.lock().on_recovery = Some(handler);
+  }
+
+  /// Only the first close gets notified, the following ones are its consequences
+  pub(crate) fn on_close(&self, reason: &CloseReason) {
+    // The handlers are called without holding the lock, so that they can register new ones
+    let (on_error, on_close) = {
+      let mut inner = self.inner.lock();
+      if inner.reason.is_some() {
+        return;
+      }
+      inner.reason = Some(reason.clone());
+      (if reason.is_error() { inner.on_error.take() } else { None }, inner.on_close.take())
+    };
+    if let Some(handler) = on_error.as_ref() {
+      handler(reason.clone());
+    }
+    if let Some(handler) = on_close.as_ref() {
+      handler(reason.clone());
+    }
+    // Put the handlers back, unless they got replaced in the meantime
+    let mut inner = self.inner.lock();
+    if inner.on_error.is_none() {
+      inner.on_error = on_error;
+    }
+    if inner.on_close.is_none() {
+      inner.on_close = on_close;
+    }
+  }
+
+  /// The channel got opened again after the server closed it
+  pub(crate) fn on_recovery(&self) {
+    let on_recovery = self.inner.lock().on_recovery.take();
+    if let Some(handler) = on_recovery.as_ref() {
+      handler();
+    }
+    let mut inner = self.inner.lock();
+    if inner.on_recovery.is_none() {
+      inner.on_recovery = on_recovery;
+    }
+  }
+}
+
+impl fmt::Debug for CloseHandler {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "CloseHandler")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  fn counter(count: &Arc<AtomicUsize>) -> Box<impl Fn(CloseReason) + Send + 'static> {
+    let count = count.clone();
+    Box::new(move |_| { count.fetch_add(1, Ordering::SeqCst); })
+  }
+
+  #[test]
+  fn handlers_registered_after_the_close_are_called_right_away() {
+    let close_handler = CloseHandler::default();
+    let (closed, errored) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    close_handler.on_close(&CloseReason::normal());
+    close_handler.set_close_handler(counter(&closed));
+    close_handler.set_error_handler(counter(&errored));
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+    assert_eq!(errored.load(Ordering::SeqCst), 0);
+    close_handler.on_close(&CloseReason::normal());
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn handlers_can_register_handlers() {
+    let close_handler = CloseHandler::default();
+    let closed        = Arc::new(AtomicUsize::new(0));
+    let (handler, count) = (close_handler.clone(), closed.clone());
+    close_handler.set_close_handler(Box::new(move |_| handler.set_close_handler(counter(&count))));
+    close_handler.on_close(&CloseReason::normal());
+    // The handler registered while closing gets called right away, and stays registered
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+    assert!(close_handler.inner.lock().on_close.is_some());
+  }
+
+  #[test]
+  fn recovery_handler_is_called_on_each_recovery() {
+    let close_handler = CloseHandler::default();
+    let recovered     = Arc::new(AtomicUsize::new(0));
+    let count         = recovered.clone();
+    close_handler.on_recovery();
+    close_handler.set_recovery_handler(Box::new(move || { count.fetch_add(1, Ordering::SeqCst); }));
+    close_handler.on_recovery();
+    close_handler.on_recovery();
+    assert_eq!(recovered.load(Ordering::SeqCst), 2);
+  }
+}
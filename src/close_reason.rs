@@ -0,0 +1,67 @@
+use amq_protocol::protocol::AMQPHardError;
+
+use std::fmt;
+
+use crate::{
+  error::{Error, ErrorKind},
+  types::{ShortString, ShortUInt},
+};
+
+pub(crate) const REPLY_SUCCESS: ShortUInt = 200;
+
+/// Why a channel or a connection got closed, either by the server, by us or because of an error
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseReason {
+  pub reply_code: ShortUInt,
+  pub reply_text: ShortString,
+  /// The class of the method which caused the close, if any
+  pub class_id:   ShortUInt,
+  /// The method which caused the close, if any
+  pub method_id:  ShortUInt,
+}
+
+impl CloseReason {
+  pub(crate) fn new(reply_code: ShortUInt, reply_text: &str, class_id: ShortUInt, method_id: ShortUInt) -> Self {
+    Self { reply_code, reply_text: reply_text.into(), class_id, method_id }
+  }
+
+  /// A regular close, without any error
+  pub(crate) fn normal() -> Self {
+    Self::new(REPLY_SUCCESS, "OK", 0, 0)
+  }
+
+  /// Whether the close was caused by an error rather than requested
+  pub fn is_error(&self) -> bool {
+    self.reply_code != REPLY_SUCCESS
+  }
+
+  pub(crate) fn channel_closed(&self) -> ErrorKind {
+    ErrorKind::ChannelClosed(self.clone())
+  }
+
+  pub(crate) fn connection_closed(&self) -> ErrorKind {
+    ErrorKind::ConnectionClosed(self.clone())
+  }
+}
+
+impl From<&Error> for CloseReason {
+  /// The reason we give up on a connection or a channel on our side
+  fn from(error: &Error) -> Self {
+    let reply_code = match error.kind() {
+      ErrorKind::ParsingError(_)  => AMQPHardError::FRAMEERROR,
+      ErrorKind::UnexpectedReply  => AMQPHardError::UNEXPECTEDFRAME,
+      _                           => AMQPHardError::INTERNALERROR,
+    };
+    Self::new(reply_code.get_id(), &error.to_string(), 0, 0)
+  }
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.reply_code, self.reply_text)?;
+    if self.class_id != 0 || self.method_id != 0 {
+      write!(f, " ({}:{})", self.class_id, self.method_id)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,194 @@
+//! Incremental encoding and decoding of AMQP frames over byte buffers
+//!
+//! This relies on the same framing as lapin itself, to build AMQP-aware tools such as proxies
+//! or test servers. With the `tokio-util` feature, `AMQPCodec` also implements `tokio_util::codec`'s
+//! `Decoder` and `Encoder`.
+
+use amq_protocol::frame::{AMQPFrame, GenError, Offset, gen_frame, parse_frame};
+
+use crate::error::{Error, ErrorKind};
+
+pub(crate) const FRAME_HEADER_SIZE: usize = 7;
+pub(crate) const FRAME_BODY:        u8    = 3;
+pub(crate) const FRAME_END:         u8    = 0xCE;
+
+/// Decodes and encodes `AMQPFrame`s
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AMQPCodec {
+  max_frame_size: Option<usize>,
+}
+
+impl AMQPCodec {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Reject the incoming frames larger than `frame_max`, as negotiated during the connection
+  pub fn with_max_frame_size(frame_max: u32) -> Self {
+    Self { max_frame_size: Some(frame_max as usize) }
+  }
+
+  /// Try to decode a frame from the beginning of `buffer`
+  ///
+  /// Returns the frame and the number of bytes it used, or `None` if more data is needed
+  pub fn decode_frame(&self, buffer: &[u8]) -> Result<Option<(AMQPFrame, usize)>, Error> {
+    if let Some(max_frame_size) = self.max_frame_size {
+      if buffer.len() >= FRAME_HEADER_SIZE {
+        let size       = u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]) as usize;
+        let frame_size = size.saturating_add(FRAME_HEADER_SIZE + 1);
+        if frame_size > max_frame_size {
+          return Err(ErrorKind::ParsingError(format!("frame of size {} is larger than the max frame size {}", frame_size, max_frame_size)).into());
+        }
+      }
+    }
+    match parse_frame(buffer) {
+      Ok((i, frame)) => Ok(Some((frame, buffer.offset(i)))),
+      Err(e)         => {
+        if e.is_incomplete() {
+          Ok(None)
+        } else {
+          Err(ErrorKind::ParsingError(format!("{:?}", e)).into())
+        }
+      },
+    }
+  }
+
+  /// Try to encode `frame` at the beginning of `buffer`
+  ///
+  /// Returns the number of bytes written, or `None` if `buffer` is too small
+  pub fn encode_frame(&self, frame: &AMQPFrame, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+    match gen_frame(buffer, frame).map(|tup| tup.0) {
+      Ok(size)                        => Ok(Some(size)),
+      Err(GenError::BufferTooSmall(_)) => Ok(None),
+      Err(e)                          => Err(ErrorKind::SerialisationError(e).into()),
+    }
+  }
+
+  /// Encode `frame` at the end of `buffer`, growing it as needed
+  pub fn encode_frame_to_vec(&self, frame: &AMQPFrame, buffer: &mut Vec<u8>) -> Result<usize, Error> {
+    let start    = buffer.len();
+    let mut room = size_hint(frame);
+    loop {
+      buffer.resize(start + room, 0);
+      match self.encode_frame(frame, &mut buffer[start..]) {
+        Ok(Some(size)) => {
+          buffer.truncate(start + size);
+          return Ok(size);
+        },
+        Ok(None)       => room = room.saturating_mul(2),
+        Err(e)         => {
+          buffer.truncate(start);
+          return Err(e);
+        },
+      }
+    }
+  }
+}
+
+/// A first guess of the size of an encoded frame
+fn size_hint(frame: &AMQPFrame) -> usize {
+  match frame {
+    AMQPFrame::Body(_, payload) => payload.len() + FRAME_HEADER_SIZE + 1,
+    _                           => 256,
+  }
+}
+
+#[cfg(feature = "tokio-util")]
+mod tokio_codec {
+  use super::*;
+
+  use bytes::{Buf, BytesMut};
+  use tokio_util::codec::{Decoder, Encoder};
+
+  impl Decoder for AMQPCodec {
+    type Item  = AMQPFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<AMQPFrame>, Error> {
+      match self.decode_frame(&src[..])? {
+        Some((frame, size)) => {
+          src.advance(size);
+          Ok(Some(frame))
+        },
+        None                => Ok(None),
+      }
+    }
+  }
+
+  impl Encoder for AMQPCodec {
+    type Item  = AMQPFrame;
+    type Error = Error;
+
+    fn encode(&mut self, frame: AMQPFrame, dst: &mut BytesMut) -> Result<(), Error> {
+      let mut buffer = Vec::new();
+      self.encode_frame_to_vec(&frame, &mut buffer)?;
+      dst.extend_from_slice(&buffer);
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encode_and_decode() {
+    let codec      = AMQPCodec::new();
+    let mut buffer = Vec::new();
+    assert_eq!(codec.encode_frame_to_vec(&AMQPFrame::Heartbeat(0), &mut buffer).ok(), Some(8));
+    assert_eq!(codec.encode_frame_to_vec(&AMQPFrame::Body(1, vec![42; 1000]), &mut buffer).ok(), Some(1008));
+    assert_eq!(&buffer[..8], &[8, 0, 0, 0, 0, 0, 0, FRAME_END]);
+
+    let (frame, size) = codec.decode_frame(&buffer).unwrap().unwrap();
+    assert_eq!(frame, AMQPFrame::Heartbeat(0));
+    assert_eq!(size, 8);
+    let (frame, size) = codec.decode_frame(&buffer[8..]).unwrap().unwrap();
+    assert_eq!(frame, AMQPFrame::Body(1, vec![42; 1000]));
+    assert_eq!(size, 1008);
+  }
+
+  #[test]
+  fn decode_incomplete_frame() {
+    let codec      = AMQPCodec::new();
+    let mut buffer = Vec::new();
+    codec.encode_frame_to_vec(&AMQPFrame::Body(1, vec![42; 1000]), &mut buffer).unwrap();
+    assert!(codec.decode_frame(&buffer[..3]).unwrap().is_none());
+    assert!(codec.decode_frame(&buffer[..500]).unwrap().is_none());
+  }
+
+  #[test]
+  fn reject_frames_too_large() {
+    let codec      = AMQPCodec::with_max_frame_size(512);
+    let mut buffer = Vec::new();
+    codec.encode_frame_to_vec(&AMQPFrame::Body(1, vec![42; 1000]), &mut buffer).unwrap();
+    assert!(codec.decode_frame(&buffer[..FRAME_HEADER_SIZE]).is_err());
+  }
+
+  #[test]
+  fn encode_in_small_buffer() {
+    let codec      = AMQPCodec::new();
+    let mut buffer = [0; 16];
+    assert_eq!(codec.encode_frame(&AMQPFrame::Body(1, vec![42; 1000]), &mut buffer).ok(), Some(None));
+  }
+
+  #[cfg(feature = "tokio-util")]
+  #[test]
+  fn tokio_codec() {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let mut codec  = AMQPCodec::new();
+    let mut buffer = BytesMut::new();
+    codec.encode(AMQPFrame::Heartbeat(0), &mut buffer).unwrap();
+    codec.encode(AMQPFrame::Body(1, vec![42; 1000]), &mut buffer).unwrap();
+    assert_eq!(buffer.len(), 1016);
+
+    let mut partial = buffer.split_to(500);
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(AMQPFrame::Heartbeat(0)));
+    assert_eq!(codec.decode(&mut partial).unwrap(), None);
+    partial.unsplit(buffer);
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(AMQPFrame::Body(1, vec![42; 1000])));
+    assert!(partial.is_empty());
+  }
+}
@@ -31,11 +31,29 @@ impl Configuration {
   pub(crate) fn set_heartbeat(&self, heartbeat: u16) {
     self.inner.write().heartbeat = heartbeat;
   }
+
+  pub fn max_logged_body_size(&self) -> usize {
+    self.inner.read().max_logged_body_size
+  }
+
+  pub(crate) fn set_max_logged_body_size(&self, max_logged_body_size: usize) {
+    self.inner.write().max_logged_body_size = max_logged_body_size;
+  }
+
+  pub fn max_buffer_size(&self) -> usize {
+    self.inner.read().max_buffer_size
+  }
+
+  pub(crate) fn set_max_buffer_size(&self, max_buffer_size: usize) {
+    self.inner.write().max_buffer_size = max_buffer_size;
+  }
 }
 
 #[derive(Debug, Default)]
 struct Inner {
-  channel_max: u16,
-  frame_max:   u32,
-  heartbeat:   u16,
+  channel_max:          u16,
+  frame_max:            u32,
+  heartbeat:            u16,
+  max_logged_body_size: usize,
+  max_buffer_size:      usize,
 }
@@ -1,6 +1,13 @@
 pub use crate::wait::NotifyReady;
 
-use std::fmt;
+use parking_lot::Mutex;
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  sync::Arc,
+  time::Duration,
+};
 
 use crate:: {
   error::Error,
@@ -16,6 +23,12 @@ impl<T, I> Confirmation<T, I> {
     Self { kind: ConfirmationKind::Wait(wait) }
   }
 
+  pub(crate) fn new_ok(value: T) -> Self {
+    let (wait, wait_handle) = Wait::new();
+    wait_handle.finish(value);
+    Self::new(wait)
+  }
+
   pub(crate) fn new_error(error: Error) -> Self {
     let (wait, wait_handle) = Wait::new();
     wait_handle.error(error);
@@ -40,6 +53,13 @@ impl<T, I> Confirmation<T, I> {
     }
   }
 
+  fn subscribe_unless_ready(&self, task: Box<dyn NotifyReady + Send>) -> bool {
+    match &self.kind {
+      ConfirmationKind::Wait(wait)   => wait.subscribe_unless_ready(task),
+      ConfirmationKind::Map(wait, _) => wait.subscribe_unless_ready(task),
+    }
+  }
+
   pub fn try_wait(&self) -> Option<Result<T, Error>> {
     match &self.kind {
       ConfirmationKind::Wait(wait)   => wait.try_wait(),
@@ -47,6 +67,14 @@ impl<T, I> Confirmation<T, I> {
     }
   }
 
+  /// Wait at most `timeout` for the result, returns None if it elapsed so that we can try again later
+  pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T, Error>> {
+    match &self.kind {
+      ConfirmationKind::Wait(wait)   => wait.wait_timeout(timeout),
+      ConfirmationKind::Map(wait, f) => wait.wait_timeout(timeout).map(|res| res.map(f)),
+    }
+  }
+
   pub fn wait(self) -> Result<T, Error> {
     match self.kind {
       ConfirmationKind::Wait(wait)   => wait.wait(),
@@ -55,12 +83,72 @@ impl<T, I> Confirmation<T, I> {
   }
 }
 
+impl<T: Send + 'static, I: Send + 'static> Confirmation<T, I> {
+  /// Call `f` with the result once it's available, without blocking
+  ///
+  /// `f` runs on the thread completing the confirmation, usually the io loop handling the reply
+  /// of the server, so it must not wait for anything itself. The sending of a frame is completed
+  /// while the outbox is locked, so this is only meant for the replies.
+  pub(crate) fn then<F: FnOnce(Result<T, Error>) + Send + 'static>(self, f: F) {
+    let slot     = Arc::new(Mutex::new(None));
+    let mut next = slot.lock();
+    if self.subscribe_unless_ready(Box::new(Then(slot.clone()))) {
+      // If the result comes in the meantime, the notification waits for the slot to be filled
+      *next = Some((self, f));
+    } else {
+      drop(next);
+      if let Some(res) = self.try_wait() {
+        f(res);
+      }
+    }
+  }
+
+  /// Only keep whether it succeeded, to chain steps replying with different types
+  pub(crate) fn ignore_value(self) -> Confirmation<()> {
+    let (wait, wait_handle) = Wait::new();
+    self.then(move |res| match res {
+      Ok(_)    => wait_handle.finish(()),
+      Err(err) => wait_handle.error(err),
+    });
+    Confirmation::new(wait)
+  }
+}
+
 impl<T> Confirmation<T> {
   pub(crate) fn map<M>(self, f: Box<dyn Fn(T) -> M + Send + 'static>) -> Confirmation<M, T> {
     Confirmation { kind: ConfirmationKind::Map(Box::new(self), f) }
   }
 }
 
+struct Then<T, I, F>(Arc<Mutex<Option<(Confirmation<T, I>, F)>>>);
+
+impl<T, I, F: FnOnce(Result<T, Error>)> NotifyReady for Then<T, I, F> {
+  fn notify(&self) {
+    let next = self.0.lock().take();
+    if let Some((confirmation, f)) = next {
+      if let Some(res) = confirmation.try_wait() {
+        f(res);
+      }
+    }
+  }
+}
+
+/// A step of `sequence`
+pub(crate) type Step = Box<dyn FnOnce() -> Confirmation<()> + Send + 'static>;
+
+/// Start the steps one after the other, each one once the previous one succeeded, then call `done`
+///
+/// Nothing blocks: the next step gets started from the reply to the previous one.
+pub(crate) fn sequence<F: FnOnce(Result<(), Error>) + Send + 'static>(mut steps: VecDeque<Step>, done: F) {
+  match steps.pop_front() {
+    Some(step) => step().then(move |res| match res {
+      Ok(())   => sequence(steps, done),
+      Err(err) => done(Err(err)),
+    }),
+    None       => done(Ok(())),
+  }
+}
+
 enum ConfirmationKind<T, I> {
   Wait(Wait<T>),
   Map(Box<Confirmation<I>>, Box<dyn Fn(I) -> T + Send + 'static>)
@@ -110,3 +198,46 @@ pub(crate) mod futures {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::error::ErrorKind;
+
+  #[test]
+  fn then_runs_once_the_result_is_there() {
+    let (wait, wait_handle) = Wait::new();
+    let result              = Arc::new(Mutex::new(None));
+    let res                 = result.clone();
+    Confirmation::<u32>::new(wait).then(move |value| *res.lock() = Some(value.unwrap()));
+    assert_eq!(*result.lock(), None);
+    wait_handle.finish(42);
+    assert_eq!(*result.lock(), Some(42));
+
+    let res = result.clone();
+    Confirmation::new_ok(1).map(Box::new(|value: u32| value + 1)).then(move |value| *res.lock() = Some(value.unwrap()));
+    assert_eq!(*result.lock(), Some(2));
+  }
+
+  #[test]
+  fn sequence_stops_at_the_first_error() {
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let step    = |id: u32, ok: bool| -> Step {
+      let started = started.clone();
+      Box::new(move || {
+        started.lock().push(id);
+        if ok { Confirmation::new_ok(()) } else { Confirmation::new_error(ErrorKind::NotConnected.into()) }
+      })
+    };
+    let (done, done_handle)       = Wait::new();
+    let (pending, pending_handle) = Wait::<()>::new();
+    let steps: VecDeque<Step>     = vec![step(1, true), Box::new(move || Confirmation::new(pending)), step(2, false), step(3, true)].into();
+    sequence(steps, move |res| done_handle.finish(res.is_ok()));
+    assert_eq!(*started.lock(), vec![1]);
+    assert!(done.try_wait().is_none());
+    pending_handle.finish(());
+    assert_eq!(*started.lock(), vec![1, 2]);
+    assert_eq!(done.try_wait().unwrap().unwrap(), false);
+  }
+}
@@ -1,54 +1,71 @@
 use amq_protocol::{
   frame::AMQPFrame,
+  protocol::{AMQPHardError, AMQPSoftError},
   tcp::TcpStream,
   uri::AMQPUri,
 };
+use bytes::Bytes;
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 use log::{debug, error, trace};
 
 use std::{
   io,
   thread::JoinHandle,
+  time::Duration,
 };
 
 use crate::{
   channel::{Channel, Reply},
+  channel_builder::ChannelBuilder,
   channels::Channels,
+  close_reason::{CloseReason, REPLY_SUCCESS},
   confirmation::Confirmation,
   configuration::Configuration,
+  delivery_budget::DeliveryBudget,
   connection_properties::ConnectionProperties,
   connection_status::{ConnectionStatus, ConnectionState},
+  diagnostics::{BufferDiagnostics, ConnectionDiagnostics},
   error::{Error, ErrorKind},
   error_handler::ErrorHandler,
-  frames::{Frames, Priority, SendId},
+  exchange::ExchangeKind,
+  frames::{Drained, Frames, OutgoingFrame, Priority, SendId},
   io_loop::{IoLoop, IoLoopHandle},
+  options::{ExchangeDeclareOptions, QueueDeclareOptions},
+  queue::Queue,
+  redacted_frame::RedactedFrame,
   registration::Registration,
   tcp::AMQPUriTcpExt,
-  types::ShortUInt,
-  wait::Wait,
+  timers::Timers,
+  types::{FieldTable, ShortUInt},
+  wait::{Wait, WaitHandle},
 };
 
 #[derive(Clone, Debug)]
 pub struct Connection {
-  configuration: Configuration,
-  status:        ConnectionStatus,
-  channels:      Channels,
-  registration:  Registration,
-  frames:        Frames,
-  io_loop:       IoLoopHandle,
-  error_handler: ErrorHandler,
+  configuration:   Configuration,
+  status:          ConnectionStatus,
+  channels:        Channels,
+  registration:    Registration,
+  frames:          Frames,
+  delivery_budget: DeliveryBudget,
+  io_loop:         IoLoopHandle,
+  error_handler:   ErrorHandler,
+  timers:          Timers,
 }
 
 impl Default for Connection {
   fn default() -> Self {
-    let connection = Self {
-      configuration: Configuration::default(),
-      status:        ConnectionStatus::default(),
-      channels:      Channels::default(),
-      registration:  Registration::default(),
-      frames:        Frames::default(),
-      io_loop:       IoLoopHandle::default(),
-      error_handler: ErrorHandler::default(),
+    let registration = Registration::default();
+    let connection   = Self {
+      configuration:   Configuration::default(),
+      status:          ConnectionStatus::default(),
+      channels:        Channels::default(),
+      delivery_budget: DeliveryBudget::new(registration.clone()),
+      registration,
+      frames:          Frames::default(),
+      io_loop:         IoLoopHandle::default(),
+      error_handler:   ErrorHandler::default(),
+      timers:          Timers::default(),
     };
 
     connection.channels.create_zero(connection.clone());
@@ -91,6 +108,68 @@ impl Connection {
     }
   }
 
+  /// Open a channel with its confirm mode, qos and handlers set up in one go
+  pub fn channel_builder(&self) -> ChannelBuilder {
+    ChannelBuilder::new(self.clone())
+  }
+
+  /// Check whether a queue exists, resolves to None if it doesn't
+  ///
+  /// The passive declare runs on a short-lived channel, as the server closes the channel it
+  /// got sent on when the queue doesn't exist.
+  pub fn queue_exists(&self, name: &str) -> Confirmation<Option<Queue>> {
+    let name    = name.to_owned();
+    let options = QueueDeclareOptions { passive: true, ..QueueDeclareOptions::default() };
+    self.passive_declare(move |channel| channel.queue_declare(&name, options, FieldTable::default()).map(Box::new(Some)), None)
+  }
+
+  /// Check whether an exchange exists, on a short-lived channel like `queue_exists`
+  pub fn exchange_exists(&self, name: &str) -> Confirmation<bool> {
+    let name    = name.to_owned();
+    let options = ExchangeDeclareOptions { passive: true, ..ExchangeDeclareOptions::default() };
+    // The server doesn't check the kind of the exchange on a passive declare
+    self.passive_declare(move |channel| channel.exchange_declare(&name, ExchangeKind::Direct, options, FieldTable::default()).map(Box::new(|()| true)), false)
+  }
+
+  /// Resolves to `not_found` if the server closed the channel with NOT_FOUND
+  ///
+  /// Nothing blocks: each step is started from the reply to the previous one.
+  fn passive_declare<T: Send + 'static, I: Send + 'static, F: FnOnce(&Channel) -> Confirmation<T, I> + Send + 'static>(&self, declare: F, not_found: T) -> Confirmation<T> {
+    let (wait, wait_handle) = Wait::new();
+    self.create_channel().then(move |res| match res {
+      Ok(channel) => declare(&channel).then(move |res| {
+        let res = match res {
+          Err(ref err) if Self::is_not_found(err) => Ok(not_found),
+          res                                     => res,
+        };
+        Self::close_passive_channel(&channel, res, wait_handle);
+      }),
+      Err(err)    => wait_handle.error(err),
+    });
+    Confirmation::new(wait)
+  }
+
+  fn is_not_found(error: &Error) -> bool {
+    match error.kind() {
+      ErrorKind::ChannelClosed(reason) => reason.reply_code == AMQPSoftError::NOTFOUND.get_id(),
+      _                                => false,
+    }
+  }
+
+  /// Close the channel of a passive declare if the server didn't already, then hand out `res`
+  fn close_passive_channel<T: Send + 'static>(channel: &Channel, res: Result<T, Error>, wait_handle: WaitHandle<T>) {
+    if !channel.status().is_connected() {
+      return match res {
+        Ok(value) => wait_handle.finish(value),
+        Err(err)  => wait_handle.error(err),
+      };
+    }
+    channel.close(REPLY_SUCCESS, "OK").then(move |close_res| match (res, close_res) {
+      (Ok(value), Ok(()))              => wait_handle.finish(value),
+      (Ok(_), Err(err)) | (Err(err), _) => wait_handle.error(err),
+    });
+  }
+
   /// Block current thread while the connection is still active.
   /// This is useful when you only have a consumer and nothing else keeping your application
   /// "alive".
@@ -110,8 +189,28 @@ impl Connection {
     &self.status
   }
 
-  pub(crate) fn flow(&self) -> bool {
-    self.channels.flow()
+  /// Take a snapshot of the internal state of this connection, its channels, queues and consumers.
+  pub fn diagnostics(&self) -> ConnectionDiagnostics {
+    let (receive_buffer, send_buffer) = self.io_loop.buffers_diagnostics();
+    ConnectionDiagnostics {
+      state:                    self.status.state().name(),
+      blocked:                  self.status.blocked(),
+      channels:                 self.channels.diagnostics(),
+      frames:                   self.frames.diagnostics(),
+      receive_buffer,
+      send_buffer,
+      buffered_deliveries_size: self.delivery_budget.buffered(),
+      reading_paused:           self.delivery_budget.is_paused(),
+    }
+  }
+
+  pub(crate) fn delivery_budget(&self) -> DeliveryBudget {
+    self.delivery_budget.clone()
+  }
+
+  /// Whether too many deliveries are waiting to be consumed to keep reading from the socket
+  pub(crate) fn reading_paused(&self) -> bool {
+    self.delivery_budget.is_paused()
   }
 
   pub fn close(&self, reply_code: ShortUInt, reply_text: &str) -> Confirmation<()> {
@@ -122,8 +221,17 @@ impl Connection {
     self.io_loop.register(io_loop);
   }
 
-  pub(crate) fn drop_pending_frames(&self) {
-    self.frames.drop_pending();
+  /// Drop what we didn't send yet, the server won't handle it anymore
+  pub(crate) fn drop_pending_frames(&self, reason: &CloseReason) {
+    self.frames.drop_pending(|| reason.connection_closed());
+  }
+
+  pub(crate) fn drop_channel_frames<F: Fn() -> ErrorKind>(&self, channel_id: u16, error: F) {
+    self.frames.drop_channel_frames(channel_id, error);
+  }
+
+  pub(crate) fn fail_expected_replies<F: Fn() -> ErrorKind>(&self, channel_id: u16, error: F) {
+    self.frames.fail_expected_replies(channel_id, error);
   }
 
   fn connector(options: ConnectionProperties) -> impl FnOnce(TcpStream, AMQPUri) -> Result<(Wait<Connection>, IoLoop<TcpStream>), Error> + 'static {
@@ -139,6 +247,10 @@ impl Connection {
       if let Some(heartbeat) = uri.query.heartbeat {
         conn.configuration.set_heartbeat(heartbeat);
       }
+      conn.configuration.set_max_logged_body_size(options.max_logged_body_size);
+      conn.configuration.set_max_buffer_size(options.max_buffer_size);
+      conn.frames.set_watermarks(options.outbox_watermarks.clone());
+      conn.delivery_budget.set_watermarks(options.max_buffered_deliveries_size, options.resume_buffered_deliveries_size);
       conn.send_frame(0, Priority::CRITICAL, AMQPFrame::ProtocolHeader, None)?;
       let (wait, wait_handle) = Wait::new();
       conn.set_state(ConnectionState::SentProtocolHeader(wait_handle, uri.authority.userinfo.into(), options));
@@ -159,6 +271,34 @@ impl Connection {
     self.status.unblock();
   }
 
+  /// The server paused or resumed the publishes on this channel
+  pub(crate) fn set_channel_flow(&self, channel_id: u16, flow: bool) -> Result<(), Error> {
+    self.frames.set_flow(channel_id, flow);
+    if flow {
+      // Wake the io loop up, the frames of the channel can be sent again
+      self.set_readable()?;
+    }
+    Ok(())
+  }
+
+  /// Run `timer` on the io loop once `delay` elapsed
+  pub(crate) fn schedule(&self, delay: Duration, timer: Box<dyn FnOnce() + Send + 'static>) {
+    self.timers.schedule(delay, timer);
+    // Wake the io loop up so that it takes the new deadline into account
+    if let Err(err) = self.set_readable() {
+      error!("Failed to wake the io loop up: {}", err);
+    }
+  }
+
+  pub(crate) fn next_timeout(&self) -> Option<Duration> {
+    self.timers.next_timeout()
+  }
+
+  /// Run the timers which are due, or all of them once the io loop stops so that nothing waits for them forever
+  pub(crate) fn run_timers(&self, all: bool) {
+    self.timers.run(all);
+  }
+
   fn set_readable(&self) -> Result<(), Error> {
     trace!("connection set readable");
     self.registration.set_readiness(Ready::readable()).map_err(ErrorKind::IOError)?;
@@ -172,24 +312,44 @@ impl Connection {
     Ok(wait)
   }
 
+  /// Whether published messages are currently kept aside until enough frames get sent
+  pub(crate) fn outbox_full(&self) -> bool {
+    self.frames.is_full()
+  }
+
+  pub(crate) fn send_frames(&self, channel_id: u16, priority: Priority, frames: Vec<OutgoingFrame>, payload: Option<Bytes>) -> Result<Wait<()>, Error> {
+    trace!("connection send_frames; channel_id={}", channel_id);
+    let wait = self.frames.push_frames(channel_id, priority, frames, payload);
+    self.set_readable()?;
+    Ok(wait)
+  }
+
   pub(crate) fn next_expected_reply(&self, channel_id: u16) -> Option<Reply> {
     self.frames.next_expected_reply(channel_id)
   }
 
-  /// next message to send to the network
+  pub(crate) fn expected_replies(&self, channel_id: u16) -> Vec<&'static str> {
+    self.frames.expected_replies(channel_id)
+  }
+
+  pub(crate) fn set_buffers_diagnostics(&self, receive_buffer: BufferDiagnostics, send_buffer: BufferDiagnostics) {
+    self.io_loop.set_buffers_diagnostics(receive_buffer, send_buffer);
+  }
+
+  /// hand the next messages to send to the network to `f`, until it cannot take more
   ///
-  /// returns None if there's no message to send
-  pub(crate) fn next_frame(&self) -> Option<(SendId, AMQPFrame)> {
-    self.frames.pop(self.flow())
+  /// returns true if there are no messages left to send
+  pub(crate) fn drain_frames<F: FnMut(SendId, OutgoingFrame) -> Result<Drained, Error>>(&self, f: F) -> Result<bool, Error> {
+    self.frames.drain(f)
   }
 
   /// updates the current state with a new received frame
   pub(crate) fn handle_frame(&self, f: AMQPFrame) -> Result<(), Error> {
-    trace!("will handle frame: {:?}", f);
+    trace!("will handle frame: {:?}", RedactedFrame::new(&f, self.configuration.max_logged_body_size()));
     match f {
       AMQPFrame::ProtocolHeader => {
         error!("error: the client should not receive a protocol header");
-        self.set_error()?;
+        self.set_error(CloseReason::new(AMQPHardError::COMMANDINVALID.get_id(), "unexpected protocol header", 0, 0))?;
       },
       AMQPFrame::Method(channel_id, method) => {
         self.channels.receive_method(channel_id, method)?;
@@ -213,35 +373,44 @@ impl Connection {
     Ok(())
   }
 
-  pub(crate) fn requeue_frame(&self, send_id: SendId, frame: AMQPFrame) -> Result<(), Error> {
-    self.set_readable()?;
-    self.frames.retry(send_id, frame);
-    Ok(())
-  }
-
   pub(crate) fn mark_sent(&self, send_id: SendId) {
     self.frames.mark_sent(send_id);
   }
 
   pub(crate) fn remove_channel(&self, channel_id: u16) -> Result<(), Error> {
-    self.frames.clear_expected_replies(channel_id);
     self.channels.remove(channel_id)
   }
 
+  pub(crate) fn reopen_channel(&self, channel: &Channel) -> Result<(), Error> {
+    self.channels.reopen(channel, self.configuration.channel_max())
+  }
+
   pub(crate) fn set_closing(&self) {
     self.set_state(ConnectionState::Closing);
     self.channels.set_closing();
   }
 
   pub(crate) fn set_closed(&self) -> Result<(), Error> {
+    let reason = self.status.set_close_reason(CloseReason::normal());
+    self.set_state(ConnectionState::Closed);
+    self.drop_pending_frames(&reason);
+    self.channels.set_closed(reason)
+  }
+
+  /// The server closed the connection: what was pending got dropped when it asked us to,
+  /// all that's left is our close-ok which still needs to be sent
+  pub(crate) fn set_closed_by_server(&self) -> Result<(), Error> {
+    let reason = self.status.set_close_reason(CloseReason::normal());
     self.set_state(ConnectionState::Closed);
-    self.channels.set_closed()
+    self.channels.set_closed(reason)
   }
 
-  pub(crate) fn set_error(&self) -> Result<(), Error> {
-    error!("Connection error");
+  pub(crate) fn set_error(&self, reason: CloseReason) -> Result<(), Error> {
+    let reason = self.status.set_close_reason(reason);
+    error!("Connection error: {}", reason);
     self.set_state(ConnectionState::Error);
-    self.channels.set_error()?;
+    self.drop_pending_frames(&reason);
+    self.channels.set_error(reason)?;
     self.error_handler.on_error();
     Ok(())
   }
@@ -301,7 +470,7 @@ mod tests {
     let queue_name = ShortString::from("consumed");
     let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
     let consumer_tag = ShortString::from("consumer-tag");
-    let consumer = Consumer::new(consumer_tag.clone());
+    let consumer = Consumer::new(consumer_tag.clone(), false, conn.delivery_budget());
     queue.register_consumer(consumer_tag.clone(), consumer);
     conn.channels.get(channel.id()).map(|c| {
       c.register_queue(queue);
@@ -353,6 +522,17 @@ mod tests {
       let expected_state = ChannelState::Connected;
       assert_eq!(channel_state, expected_state);
     }
+    {
+      // Nothing can consume the delivery, it is requeued
+      let mut nacked = Vec::new();
+      conn.drain_frames(|_, frame| {
+        if let OutgoingFrame::Frame(AMQPFrame::Method(_, AMQPClass::Basic(basic::AMQPMethod::Nack(nack)))) = frame {
+          nacked.push((nack.delivery_tag, nack.requeue));
+        }
+        Ok(Drained::Sent)
+      }).unwrap();
+      assert_eq!(nacked, vec![(1, true)]);
+    }
   }
 
   #[test]
@@ -371,7 +551,7 @@ mod tests {
     let queue_name = ShortString::from("consumed");
     let mut queue: QueueState = Queue::new(queue_name.clone(), 0, 0).into();
     let consumer_tag = ShortString::from("consumer-tag");
-    let consumer = Consumer::new(consumer_tag.clone());
+    let consumer = Consumer::new(consumer_tag.clone(), false, conn.delivery_budget());
     queue.register_consumer(consumer_tag.clone(), consumer);
     conn.channels.get(channel.id()).map(|c| {
       c.register_queue(queue);
@@ -417,4 +597,373 @@ mod tests {
       assert_eq!(channel_state, expected_state);
     }
   }
+
+  fn not_found_close(channel_id: u16) -> AMQPFrame {
+    use amq_protocol::protocol::channel;
+
+    AMQPFrame::Method(
+      channel_id,
+      AMQPClass::Channel(
+        channel::AMQPMethod::Close(
+          channel::Close {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no such thing".into(),
+            class_id: 50,
+            method_id: 10,
+          }
+        )
+      )
+    )
+  }
+
+  #[test]
+  fn missing_queues_and_exchanges_do_not_exist() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let open_ok = |channel_id| AMQPFrame::Method(channel_id, AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())));
+
+    let queue_exists = conn.queue_exists("queue");
+    conn.handle_frame(open_ok(1)).unwrap();
+    assert_eq!(conn.expected_replies(1), vec!["queue.declare-ok"]);
+    conn.handle_frame(not_found_close(1)).unwrap();
+    assert!(queue_exists.try_wait().unwrap().unwrap().is_none());
+    assert!(conn.channels.get(1).is_none());
+
+    let exchange_exists = conn.exchange_exists("exchange");
+    conn.handle_frame(open_ok(2)).unwrap();
+    assert_eq!(conn.expected_replies(2), vec!["exchange.declare-ok"]);
+    conn.handle_frame(not_found_close(2)).unwrap();
+    assert!(!exchange_exists.try_wait().unwrap().unwrap());
+  }
+
+  #[test]
+  fn existing_exchanges_exist_once_the_channel_is_closed() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::{channel, exchange};
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let exchange_exists = conn.exchange_exists("exchange");
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Exchange(exchange::AMQPMethod::DeclareOk(exchange::DeclareOk {})))).unwrap();
+    assert_eq!(conn.expected_replies(1), vec!["channel.close-ok"]);
+    assert!(exchange_exists.try_wait().is_none());
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})))).unwrap();
+    assert!(exchange_exists.try_wait().unwrap().unwrap());
+  }
+
+  #[test]
+  fn channel_closed_by_the_server() {
+    let _ = env_logger::try_init();
+
+    use crate::options::QueueDeclareOptions;
+    use crate::types::FieldTable;
+    use amq_protocol::protocol::channel;
+    use std::sync::mpsc;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    let (errors, errored) = mpsc::channel();
+    let (closes, closed) = mpsc::channel();
+    channel.on_error(Box::new(move |reason| errors.send(reason).unwrap()));
+    channel.on_close(Box::new(move |reason| closes.send(reason).unwrap()));
+    let declare = channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::default());
+    let close_frame = AMQPFrame::Method(
+      channel.id(),
+      AMQPClass::Channel(
+        channel::AMQPMethod::Close(
+          channel::Close {
+            reply_code: 406,
+            reply_text: "PRECONDITION_FAILED - inequivalent arg 'durable'".into(),
+            class_id: 50,
+            method_id: 10,
+          }
+        )
+      )
+    );
+    conn.handle_frame(close_frame).unwrap();
+    let reason = CloseReason::new(406, "PRECONDITION_FAILED - inequivalent arg 'durable'", 50, 10);
+    assert_eq!(channel.status().state(), ChannelState::Closed);
+    assert_eq!(channel.close_reason(), Some(reason.clone()));
+    assert_eq!(errored.try_recv().ok(), Some(reason.clone()));
+    assert_eq!(closed.try_recv().ok(), Some(reason.clone()));
+    match declare.try_wait() {
+      Some(Err(error)) => match error.kind() {
+        ErrorKind::ChannelClosed(error_reason) => assert_eq!(error_reason, &reason),
+        kind                                   => panic!("unexpected error: {:?}", kind),
+      },
+      res => panic!("unexpected result: {:?}", res.map(|res| res.is_ok())),
+    }
+  }
+
+  #[test]
+  fn channel_recovered_after_a_server_close() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+    use std::{sync::mpsc, thread, time::{Duration, Instant}};
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    channel.enable_recovery();
+    let (closes, closed) = mpsc::channel();
+    let (recoveries, recovered) = mpsc::channel();
+    channel.on_close(Box::new(move |reason| closes.send(reason).unwrap()));
+    channel.on_recovery(Box::new(move || recoveries.send(()).unwrap()));
+    let clone = channel.clone();
+    let old_id = channel.id();
+    let close_frame = AMQPFrame::Method(
+      old_id,
+      AMQPClass::Channel(
+        channel::AMQPMethod::Close(
+          channel::Close {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no queue 'queue'".into(),
+            class_id: 50,
+            method_id: 10,
+          }
+        )
+      )
+    );
+    conn.handle_frame(close_frame).unwrap();
+    // Run the timers as the io loop would, until the channel.open gets sent on the new id
+    let start = Instant::now();
+    while channel.id() == old_id || conn.expected_replies(channel.id()).is_empty() {
+      assert!(start.elapsed() < Duration::from_secs(5), "the channel didn't get reopened");
+      thread::sleep(Duration::from_millis(10));
+      conn.run_timers(false);
+    }
+    assert!(conn.channels.get(old_id).is_none());
+    assert_eq!(clone.id(), channel.id());
+    assert!(clone.status().is_initializing());
+    assert_eq!(clone.close_reason(), None);
+    let open_ok_frame = AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())));
+    conn.handle_frame(open_ok_frame).unwrap();
+    assert_eq!(clone.status().state(), ChannelState::Connected);
+    assert!(recovered.try_recv().is_ok());
+    assert!(closed.try_recv().is_err());
+  }
+
+  #[test]
+  fn failed_recoveries_are_notified() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+    use std::sync::mpsc;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    channel.enable_recovery();
+    let (errors, errored) = mpsc::channel();
+    channel.on_error(Box::new(move |reason| errors.send(reason).unwrap()));
+    let close_frame = AMQPFrame::Method(
+      channel.id(),
+      AMQPClass::Channel(
+        channel::AMQPMethod::Close(
+          channel::Close {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no queue 'queue'".into(),
+            class_id: 50,
+            method_id: 10,
+          }
+        )
+      )
+    );
+    conn.handle_frame(close_frame).unwrap();
+    // Nothing gets notified until the recovery gave up
+    assert!(errored.try_recv().is_err());
+    conn.set_state(ConnectionState::Closing);
+    conn.run_timers(true);
+    assert_eq!(errored.try_recv().ok(), Some(CloseReason::new(404, "NOT_FOUND - no queue 'queue'", 50, 10)));
+  }
+
+  #[test]
+  fn flow_waiters_resume_with_the_flow_and_fail_on_close() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    let flow = |active| AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::Flow(channel::Flow { active })));
+    conn.handle_frame(flow(false)).unwrap();
+    assert!(!channel.flow());
+    let resumed = channel.wait_for_flow();
+    assert!(resumed.try_wait().is_none());
+    conn.handle_frame(flow(true)).unwrap();
+    assert!(resumed.try_wait().unwrap().is_ok());
+
+    conn.handle_frame(flow(false)).unwrap();
+    let closed = channel.wait_for_flow();
+    conn.handle_frame(not_found_close(channel.id())).unwrap();
+    match closed.try_wait() {
+      Some(Err(error)) => match error.kind() {
+        ErrorKind::ChannelClosed(reason) => assert_eq!(reason.reply_code, 404),
+        kind                             => panic!("unexpected error: {:?}", kind),
+      },
+      res => panic!("unexpected result: {:?}", res.map(|res| res.is_ok())),
+    }
+  }
+
+  #[test]
+  fn diagnostics_snapshot() {
+    let _ = env_logger::try_init();
+
+    use crate::options::QueueDeclareOptions;
+    use crate::types::FieldTable;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    channel.status().set_confirm();
+    let _declare = channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::default());
+    conn.set_buffers_diagnostics(BufferDiagnostics { capacity: 1024, available_data: 42 }, BufferDiagnostics { capacity: 2048, available_data: 0 });
+
+    let diagnostics = conn.diagnostics();
+    assert_eq!(diagnostics.state, "Connected");
+    assert_eq!(diagnostics.channels.iter().map(|channel| channel.id).collect::<Vec<_>>(), vec![0, channel.id()]);
+    assert!(diagnostics.channels[1].confirm);
+    assert_eq!(diagnostics.channels[1].expected_replies, vec!["queue.declare-ok"]);
+    assert_eq!(diagnostics.frames.frames, 1);
+    assert_eq!(diagnostics.receive_buffer, BufferDiagnostics { capacity: 1024, available_data: 42 });
+    assert_eq!(diagnostics.send_buffer, BufferDiagnostics { capacity: 2048, available_data: 0 });
+  }
+
+  #[test]
+  fn concurrent_publishes_are_not_interleaved() {
+    let _ = env_logger::try_init();
+
+    use crate::options::BasicPublishOptions;
+    use std::thread;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    // 8 bytes of payload per body frame, so 5 body frames per message
+    conn.configuration.set_frame_max(16);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    let publishers = [b'a', b'b'].iter().map(|&marker| {
+      let channel = channel.clone();
+      thread::spawn(move || {
+        for _ in 0..20 {
+          channel.basic_publish("", "queue", BasicPublishOptions::default(), vec![marker; 40], BasicProperties::default());
+        }
+      })
+    }).collect::<Vec<_>>();
+    for publisher in publishers {
+      publisher.join().unwrap();
+    }
+
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+    conn.drain_frames(|_, frame| {
+      match frame {
+        OutgoingFrame::Frame(AMQPFrame::Method(..)) => messages.push(Vec::new()),
+        OutgoingFrame::Frame(AMQPFrame::Header(..)) => assert!(messages.last().unwrap().is_empty()),
+        OutgoingFrame::Body(_, payload)             => messages.last_mut().unwrap().extend_from_slice(&payload),
+        OutgoingFrame::Frame(frame)                 => panic!("unexpected frame: {:?}", frame),
+      }
+      Ok(Drained::Sent)
+    }).unwrap();
+    assert_eq!(messages.len(), 40);
+    for message in messages {
+      assert_eq!(message.len(), 40);
+      assert!(message.iter().all(|byte| *byte == message[0]));
+    }
+  }
+
+  #[test]
+  fn closing_fails_the_parked_publishes() {
+    let _ = env_logger::try_init();
+
+    use crate::connection_properties::OutboxWatermarks;
+    use crate::options::BasicPublishOptions;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    conn.configuration.set_frame_max(4096);
+    conn.frames.set_watermarks(OutboxWatermarks { high_bytes: 10, high_frames: 100, low_bytes: 4, low_frames: 100 });
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    let queued = channel.basic_publish("", "queue", BasicPublishOptions::default(), vec![0; 12], BasicProperties::default());
+    let parked = channel.basic_publish("", "queue", BasicPublishOptions::default(), Bytes::from_static(b"parked"), BasicProperties::default());
+    assert!(conn.diagnostics().frames.parked_frames > 0);
+
+    conn.set_closed().unwrap();
+    assert_eq!(parked.wait().unwrap_err().unsent_payload(), Some(Bytes::from_static(b"parked")));
+    assert!(queued.wait().is_err());
+    assert_eq!(conn.diagnostics().frames.parked_frames, 0);
+    assert_eq!(conn.diagnostics().frames.low_prio_frames, 0);
+  }
+
+  #[test]
+  fn publish_confirms_time_out() {
+    let _ = env_logger::try_init();
+
+    use crate::options::BasicPublishOptions;
+    use std::time::Duration;
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    conn.configuration.set_frame_max(2048);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    channel.status().set_confirm();
+    let confirm = channel.basic_publish_with_timeout("", "queue", BasicPublishOptions::default(), b"payload".to_vec(), BasicProperties::default(), Duration::from_millis(0));
+    conn.run_timers(false);
+    match confirm.wait() {
+      Err(error) => match error.kind() {
+        ErrorKind::ConfirmTimeout => {},
+        kind                      => panic!("unexpected error: {:?}", kind),
+      },
+      Ok(confirm) => panic!("the confirm should have timed out: {:?}", confirm),
+    }
+    // The late confirm is ignored instead of being treated as an unknown delivery tag
+    let ack_frame = AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 1, multiple: false })));
+    conn.handle_frame(ack_frame).unwrap();
+    assert!(channel.status().is_connected());
+  }
+
+  #[test]
+  fn transaction_rejected_in_confirm_mode() {
+    let _ = env_logger::try_init();
+
+    let conn = Connection::default();
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.channels.create(conn.clone()).unwrap();
+    channel.set_state(ChannelState::Connected);
+    channel.status().set_confirm();
+    let res = channel.transaction(|_| -> Result<(), Error> { panic!("the transaction shouldn't run") });
+    match res {
+      Err(error) => match error.kind() {
+        ErrorKind::TransactionInConfirmMode => {},
+        kind                                => panic!("unexpected error: {:?}", kind),
+      },
+      Ok(()) => panic!("the transaction should have been rejected"),
+    }
+  }
 }
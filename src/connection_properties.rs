@@ -5,17 +5,59 @@ use crate::{
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ConnectionProperties {
-  pub mechanism:         SASLMechanism,
-  pub locale:            String,
-  pub client_properties: FieldTable,
+  pub mechanism:            SASLMechanism,
+  pub locale:               String,
+  pub client_properties:    FieldTable,
+  /// The number of bytes of a body frame displayed when logging frames
+  pub max_logged_body_size: usize,
+  /// The maximum size of each of the send and receive buffers. Frames larger than
+  /// this are handled incrementally. It should not be lower than 4096 bytes.
+  pub max_buffer_size:      usize,
+  /// When to stop and resume queuing published messages
+  pub outbox_watermarks:    OutboxWatermarks,
+  /// Stop reading from the socket once the deliveries waiting to be consumed reach this size, in bytes.
+  /// This applies to the whole connection, on top of the per channel prefetch set with `basic_qos`.
+  pub max_buffered_deliveries_size:    usize,
+  /// Resume reading from the socket once the deliveries waiting to be consumed get back under this size
+  pub resume_buffered_deliveries_size: usize,
+}
+
+/// Limits on the frames queued for sending
+///
+/// Once either of the high watermarks is reached, published messages are kept aside
+/// until both the size and the number of queued frames get back under the low watermarks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxWatermarks {
+  /// The size of the queued body frames, in bytes
+  pub high_bytes:  usize,
+  /// The number of queued frames
+  pub high_frames: usize,
+  pub low_bytes:   usize,
+  pub low_frames:  usize,
+}
+
+impl Default for OutboxWatermarks {
+  fn default() -> Self {
+    Self {
+      high_bytes:  64 * 1024 * 1024,
+      high_frames: 64 * 1024,
+      low_bytes:   32 * 1024 * 1024,
+      low_frames:  32 * 1024,
+    }
+  }
 }
 
 impl Default for ConnectionProperties {
   fn default() -> Self {
     Self {
-      mechanism:         SASLMechanism::default(),
-      locale:            "en_US".into(),
-      client_properties: FieldTable::default(),
+      mechanism:            SASLMechanism::default(),
+      locale:               "en_US".into(),
+      client_properties:    FieldTable::default(),
+      max_logged_body_size: 64,
+      max_buffer_size:      4 * 1024 * 1024,
+      outbox_watermarks:    OutboxWatermarks::default(),
+      max_buffered_deliveries_size:    64 * 1024 * 1024,
+      resume_buffered_deliveries_size: 32 * 1024 * 1024,
     }
   }
 }
@@ -5,6 +5,7 @@ use std::sync::Arc;
 use crate::{
   Connection, ConnectionProperties,
   auth::Credentials,
+  close_reason::CloseReason,
   wait::WaitHandle,
 };
 
@@ -57,6 +58,15 @@ impl ConnectionStatus {
   pub fn errored(&self) -> bool {
     self.inner.read().state == ConnectionState::Error
   }
+
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.inner.read().close_reason.clone()
+  }
+
+  /// Only the first reason is kept, as it's the one which led to the other ones, and returned
+  pub(crate) fn set_close_reason(&self, reason: CloseReason) -> CloseReason {
+    self.inner.write().close_reason.get_or_insert(reason).clone()
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -77,6 +87,21 @@ impl Default for ConnectionState {
   }
 }
 
+impl ConnectionState {
+  pub(crate) fn name(&self) -> &'static str {
+    match self {
+      ConnectionState::Initial                => "Initial",
+      ConnectionState::SentProtocolHeader(..) => "SentProtocolHeader",
+      ConnectionState::SentStartOk(..)        => "SentStartOk",
+      ConnectionState::SentOpen(_)            => "SentOpen",
+      ConnectionState::Connected              => "Connected",
+      ConnectionState::Closing                => "Closing",
+      ConnectionState::Closed                 => "Closed",
+      ConnectionState::Error                  => "Error",
+    }
+  }
+}
+
 impl PartialEq for ConnectionState {
   fn eq(&self, other: &Self) -> bool {
     match (self, other) {
@@ -95,17 +120,19 @@ impl PartialEq for ConnectionState {
 
 #[derive(Debug)]
 struct Inner {
-  state:   ConnectionState,
-  vhost:   String,
-  blocked: bool,
+  state:        ConnectionState,
+  vhost:        String,
+  blocked:      bool,
+  close_reason: Option<CloseReason>,
 }
 
 impl Default for Inner {
   fn default() -> Self {
     Self {
-      state:   ConnectionState::default(),
-      vhost:   "/".into(),
-      blocked: false,
+      state:        ConnectionState::default(),
+      vhost:        "/".into(),
+      blocked:      false,
+      close_reason: None,
     }
   }
 }
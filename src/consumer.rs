@@ -1,4 +1,4 @@
-use log::trace;
+use log::{trace, warn};
 use parking_lot::{Mutex, MutexGuard};
 
 use std::{
@@ -9,6 +9,9 @@ use std::{
 
 use crate::{
   BasicProperties,
+  acknowledgement::DeliveryTag,
+  delivery_budget::DeliveryBudget,
+  diagnostics::ConsumerDiagnostics,
   message::Delivery,
   types::ShortString,
   wait::NotifyReady,
@@ -20,15 +23,26 @@ pub trait ConsumerDelegate: Send + Sync {
   fn on_canceled(&self) {}
 }
 
-#[derive(Clone)]
 pub struct Consumer {
   inner: Arc<Mutex<ConsumerInner>>,
+  /// Whether this is one of the handles given to the user, rather than the one kept by the channel
+  user:  bool,
 }
 
 impl Consumer {
-  pub(crate) fn new(consumer_tag: ShortString) -> Consumer {
+  pub(crate) fn new(consumer_tag: ShortString, no_ack: bool, delivery_budget: DeliveryBudget) -> Consumer {
     Consumer {
-      inner: Arc::new(Mutex::new(ConsumerInner::new(consumer_tag))),
+      inner: Arc::new(Mutex::new(ConsumerInner::new(consumer_tag, no_ack, delivery_budget))),
+      user:  false,
+    }
+  }
+
+  /// A handle for the user, once all of them are dropped nothing can read the buffered deliveries anymore
+  pub(crate) fn user_handle(&self) -> Consumer {
+    self.inner().add_user();
+    Consumer {
+      inner: self.inner.clone(),
+      user:  true,
     }
   }
 
@@ -38,7 +52,7 @@ impl Consumer {
 
   pub fn set_delegate(&self, delegate: Box<dyn ConsumerDelegate>) {
     let mut inner = self.inner();
-    for delivery in inner.deliveries.drain(..) {
+    while let Some(delivery) = inner.next_delivery() {
       delegate.on_new_delivery(delivery);
     }
     inner.delegate = Some(delegate);
@@ -54,17 +68,17 @@ impl Consumer {
     }
   }
 
-  pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>) {
+  pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>, remaining_size: usize) {
     if let Some(delivery) = self.inner().current_message.as_mut() {
-      delivery.receive_content(payload);
+      delivery.receive_content(payload, remaining_size);
     }
   }
 
-  pub(crate) fn new_delivery_complete(&mut self) {
+  /// Returns the tag of the delivery if nobody can consume it, for it to be nacked
+  pub(crate) fn new_delivery_complete(&mut self) -> Option<DeliveryTag> {
     let mut inner = self.inner();
-    if let Some(delivery) = inner.current_message.take() {
-      inner.new_delivery(delivery);
-    }
+    let delivery  = inner.current_message.take()?;
+    inner.new_delivery(delivery)
   }
 
   pub(crate) fn drop_prefetched_messages(&self) {
@@ -74,6 +88,16 @@ impl Consumer {
   pub(crate) fn cancel(&self) {
     self.inner().cancel();
   }
+
+  pub(crate) fn diagnostics(&self) -> ConsumerDiagnostics {
+    let inner = self.inner();
+    ConsumerDiagnostics {
+      tag:                 inner.tag.clone(),
+      buffered_deliveries: inner.deliveries.len(),
+      has_delegate:        inner.delegate.is_some(),
+      canceled:            inner.canceled,
+    }
+  }
 }
 
 pub struct ConsumerInner {
@@ -83,6 +107,9 @@ pub struct ConsumerInner {
   canceled:        bool,
   tag:             ShortString,
   delegate:        Option<Box<dyn ConsumerDelegate>>,
+  delivery_budget: DeliveryBudget,
+  users:           usize,
+  no_ack:          bool,
 }
 
 impl fmt::Debug for ConsumerInner {
@@ -92,7 +119,7 @@ impl fmt::Debug for ConsumerInner {
 }
 
 impl ConsumerInner {
-  fn new(consumer_tag: ShortString) -> Self {
+  fn new(consumer_tag: ShortString, no_ack: bool, delivery_budget: DeliveryBudget) -> Self {
     Self {
       current_message: None,
       deliveries:      VecDeque::new(),
@@ -100,11 +127,18 @@ impl ConsumerInner {
       canceled:        false,
       tag:             consumer_tag,
       delegate:        None,
+      delivery_budget,
+      users:           0,
+      no_ack,
     }
   }
 
   pub fn next_delivery(&mut self) -> Option<Delivery> {
-    self.deliveries.pop_front()
+    let delivery = self.deliveries.pop_front();
+    if let Some(delivery) = delivery.as_ref() {
+      self.delivery_budget.remove(delivery.data.len());
+    }
+    delivery
   }
 
   pub fn set_task(&mut self, task: Box<dyn NotifyReady + Send>) {
@@ -123,16 +157,26 @@ impl ConsumerInner {
     &self.tag
   }
 
-  fn new_delivery(&mut self, delivery: Delivery) {
+  fn new_delivery(&mut self, delivery: Delivery) -> Option<DeliveryTag> {
     trace!("new_delivery; consumer_tag={}", self.tag);
     if let Some(delegate) = self.delegate.as_ref() {
       delegate.on_new_delivery(delivery);
+    } else if self.users == 0 {
+      // Nobody can consume it, don't hold the connection back for it
+      if self.no_ack {
+        warn!("consumer abandoned, losing delivery; consumer_tag={}, delivery_tag={}", self.tag, delivery.delivery_tag);
+        return None;
+      }
+      warn!("consumer abandoned, requeuing delivery; consumer_tag={}, delivery_tag={}", self.tag, delivery.delivery_tag);
+      return Some(delivery.delivery_tag);
     } else {
+      self.delivery_budget.add(delivery.data.len());
       self.deliveries.push_back(delivery);
     }
     if let Some(task) = self.task.as_ref() {
       task.notify();
     }
+    None
   }
 
   fn drop_prefetched_messages(&mut self) {
@@ -140,7 +184,28 @@ impl ConsumerInner {
     if let Some(delegate) = self.delegate.as_ref() {
       delegate.drop_prefetched_messages();
     }
-    self.deliveries.clear();
+    self.clear_deliveries();
+  }
+
+  fn add_user(&mut self) {
+    self.users += 1;
+  }
+
+  fn remove_user(&mut self) {
+    self.users -= 1;
+    if self.users == 0 && self.delegate.is_none() {
+      trace!("consumer abandoned; consumer_tag={}", self.tag);
+      if !self.deliveries.is_empty() {
+        warn!("consumer abandoned, dropping {} buffered deliveries; consumer_tag={}", self.deliveries.len(), self.tag);
+      }
+      self.clear_deliveries();
+      self.task.take();
+    }
+  }
+
+  fn clear_deliveries(&mut self) {
+    let size: usize = self.deliveries.drain(..).map(|delivery| delivery.data.len()).sum();
+    self.delivery_budget.remove(size);
   }
 
   fn cancel(&mut self) {
@@ -148,12 +213,33 @@ impl ConsumerInner {
     if let Some(delegate) = self.delegate.as_ref() {
       delegate.on_canceled();
     }
-    self.deliveries.clear();
+    self.clear_deliveries();
     self.canceled = true;
     self.task.take();
   }
 }
 
+impl Clone for Consumer {
+  fn clone(&self) -> Self {
+    if self.user {
+      self.user_handle()
+    } else {
+      Consumer {
+        inner: self.inner.clone(),
+        user:  false,
+      }
+    }
+  }
+}
+
+impl Drop for Consumer {
+  fn drop(&mut self) {
+    if self.user {
+      self.inner().remove_user();
+    }
+  }
+}
+
 impl fmt::Debug for Consumer {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "Consumer({})", self.inner().tag())
@@ -196,3 +282,60 @@ mod futures {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::registration::Registration;
+
+  fn deliver(consumer: &mut Consumer, size: usize) -> Option<DeliveryTag> {
+    let mut delivery = Delivery::new(1, "".into(), "queue".into(), false);
+    delivery.receive_content(vec![0; size], size);
+    consumer.start_new_delivery(delivery);
+    consumer.new_delivery_complete()
+  }
+
+  #[test]
+  fn consuming_deliveries_resumes_reading() {
+    let budget       = DeliveryBudget::new(Registration::default());
+    budget.set_watermarks(10, 6);
+    let mut consumer = Consumer::new("consumer".into(), false, budget.clone());
+    let user         = consumer.user_handle();
+    deliver(&mut consumer, 6);
+    deliver(&mut consumer, 6);
+    assert!(budget.is_paused());
+    assert_eq!(user.inner().next_delivery().unwrap().data.len(), 6);
+    assert!(!budget.is_paused());
+    assert_eq!(budget.buffered(), 6);
+  }
+
+  #[test]
+  fn abandoned_consumers_release_their_deliveries() {
+    let budget       = DeliveryBudget::new(Registration::default());
+    budget.set_watermarks(10, 4);
+    let mut consumer = Consumer::new("consumer".into(), false, budget.clone());
+    let user         = consumer.user_handle();
+    let other_user   = user.clone();
+    deliver(&mut consumer, 12);
+    assert!(budget.is_paused());
+    drop(user);
+    assert!(budget.is_paused());
+    drop(other_user);
+    assert!(!budget.is_paused());
+    assert_eq!(budget.buffered(), 0);
+    // The following deliveries are handed back to be requeued
+    assert_eq!(deliver(&mut consumer, 12), Some(1));
+    assert_eq!(budget.buffered(), 0);
+    assert_eq!(consumer.diagnostics().buffered_deliveries, 0);
+  }
+
+  #[test]
+  fn abandoned_consumers_without_ack_lose_their_deliveries() {
+    let budget       = DeliveryBudget::new(Registration::default());
+    let mut consumer = Consumer::new("consumer".into(), true, budget.clone());
+    drop(consumer.user_handle());
+    assert_eq!(deliver(&mut consumer, 12), None);
+    assert_eq!(budget.buffered(), 0);
+  }
+}
@@ -0,0 +1,99 @@
+use log::trace;
+use mio::Ready;
+use parking_lot::Mutex;
+
+use std::sync::Arc;
+
+use crate::registration::Registration;
+
+/// Keeps track of the size of the deliveries waiting in the consumers of a connection, so that
+/// we stop reading from the socket while they're not consumed fast enough
+#[derive(Clone, Debug)]
+pub(crate) struct DeliveryBudget {
+  inner:        Arc<Mutex<Inner>>,
+  registration: Registration,
+}
+
+#[derive(Debug)]
+struct Inner {
+  buffered: usize,
+  high:     usize,
+  low:      usize,
+  paused:   bool,
+}
+
+impl DeliveryBudget {
+  pub(crate) fn new(registration: Registration) -> Self {
+    Self {
+      inner: Arc::new(Mutex::new(Inner {
+        buffered: 0,
+        high:     usize::max_value(),
+        low:      usize::max_value(),
+        paused:   false,
+      })),
+      registration,
+    }
+  }
+
+  pub(crate) fn set_watermarks(&self, high: usize, low: usize) {
+    let mut inner = self.inner.lock();
+    inner.high    = high;
+    inner.low     = low;
+  }
+
+  pub(crate) fn buffered(&self) -> usize {
+    self.inner.lock().buffered
+  }
+
+  /// Whether we should stop reading from the socket
+  pub(crate) fn is_paused(&self) -> bool {
+    self.inner.lock().paused
+  }
+
+  pub(crate) fn add(&self, size: usize) {
+    let mut inner = self.inner.lock();
+    inner.buffered += size;
+    if !inner.paused && inner.buffered >= inner.high {
+      trace!("{} bytes of deliveries are buffered, pausing reading", inner.buffered);
+      inner.paused = true;
+    }
+  }
+
+  pub(crate) fn remove(&self, size: usize) {
+    let resume = {
+      let mut inner  = self.inner.lock();
+      inner.buffered = inner.buffered.saturating_sub(size);
+      if inner.paused && inner.buffered <= inner.low {
+        trace!("{} bytes of deliveries are buffered, resuming reading", inner.buffered);
+        inner.paused = false;
+        true
+      } else {
+        false
+      }
+    };
+    if resume {
+      // Wake the io loop up so that it starts reading again
+      let _ = self.registration.set_readiness(Ready::readable());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reading_pauses_until_the_low_watermark() {
+    let budget = DeliveryBudget::new(Registration::default());
+    budget.set_watermarks(10, 4);
+    budget.add(6);
+    assert!(!budget.is_paused());
+    budget.add(6);
+    assert!(budget.is_paused());
+    budget.remove(6);
+    assert!(budget.is_paused());
+    budget.remove(2);
+    assert!(!budget.is_paused());
+    assert_eq!(budget.buffered(), 4);
+  }
+}
@@ -0,0 +1,80 @@
+use crate::{
+  channel_status::ChannelState,
+  types::{LongLongUInt, ShortString},
+};
+
+/// A snapshot of the internal state of a `Connection`, meant to help
+/// understanding what lapin is waiting for.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionDiagnostics {
+  pub state:                    &'static str,
+  pub blocked:                  bool,
+  pub channels:                 Vec<ChannelDiagnostics>,
+  pub frames:                   FramesDiagnostics,
+  pub receive_buffer:           BufferDiagnostics,
+  pub send_buffer:              BufferDiagnostics,
+  /// The size of the deliveries waiting to be consumed
+  pub buffered_deliveries_size: usize,
+  /// Whether we stopped reading from the socket until the deliveries get consumed
+  pub reading_paused:           bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelDiagnostics {
+  pub id:                         u16,
+  pub state:                      ChannelState,
+  pub confirm:                    bool,
+  pub send_flow:                  bool,
+  /// The replies we're waiting for from the server, in order
+  pub expected_replies:           Vec<&'static str>,
+  pub queues:                     Vec<QueueDiagnostics>,
+  /// The delivery tags of the published messages not yet confirmed by the server
+  pub unconfirmed_delivery_tags:  Vec<LongLongUInt>,
+  /// The returned messages waiting for `wait_for_confirms`
+  pub buffered_returned_messages: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QueueDiagnostics {
+  pub name:        ShortString,
+  pub consumers:   Vec<ConsumerDiagnostics>,
+  pub pending_get: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConsumerDiagnostics {
+  pub tag:                 ShortString,
+  /// The deliveries received but not yet consumed from the stream
+  pub buffered_deliveries: usize,
+  pub has_delegate:        bool,
+  pub canceled:            bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FramesDiagnostics {
+  pub priority_frames: usize,
+  pub frames:          usize,
+  pub low_prio_frames: usize,
+  /// The frames which have been queued but whose sending hasn't been acknowledged yet
+  pub pending_sends:   usize,
+  /// The size of the queued body frames
+  pub queued_bytes:    usize,
+  /// The published frames waiting for the queues to get back under the low watermark
+  pub parked_frames:   usize,
+  /// Whether the high watermark has been reached
+  pub full:            bool,
+  /// The channels on which the server asked us to stop publishing
+  pub paused_channels: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BufferDiagnostics {
+  pub capacity:       usize,
+  pub available_data: usize,
+}
@@ -2,11 +2,15 @@ use amq_protocol::{
   frame::GenError,
   protocol::AMQPClass,
 };
+use bytes::Bytes;
 use failure::{Backtrace, Context, Fail};
 
 use std::{fmt, io};
 
-use crate::connection_status::ConnectionState;
+use crate::{
+  close_reason::CloseReason,
+  connection_status::ConnectionState,
+};
 
 /// The type of error that can be returned in this crate.
 ///
@@ -38,6 +42,21 @@ pub enum ErrorKind {
   SerialisationError(GenError),
   IOError(io::Error),
   IoLoopError,
+  OutboxFull,
+  /// The channel got closed before the operation completed
+  ChannelClosed(CloseReason),
+  /// The connection got closed before the operation completed
+  ConnectionClosed(CloseReason),
+  /// The channel or the connection got closed before the message could be sent, here is its payload back
+  MessageNotSent(Bytes, Box<ErrorKind>),
+  /// AMQP forbids using transactions on a channel in confirm mode
+  TransactionInConfirmMode,
+  /// The arguments can't be used together
+  InvalidArguments(String),
+  /// The value of a `Wait` was already taken
+  AlreadyConsumed,
+  /// The server neither acked nor nacked the publish in time
+  ConfirmTimeout,
   /// A hack to prevent developers from exhaustively match on the enum's variants
   ///
   /// The purpose of this variant is to let the `ErrorKind` enumeration grow more variants
@@ -65,6 +84,14 @@ impl fmt::Display for ErrorKind {
       SerialisationError(e) => write!(f, "Failed to serialise: {:?}", e),
       IOError(e) => write!(f, "IO error: {:?}", e),
       IoLoopError => write!(f, "IO loop error"),
+      OutboxFull => write!(f, "too many frames are waiting to be sent"),
+      ChannelClosed(reason) => write!(f, "channel closed: {}", reason),
+      ConnectionClosed(reason) => write!(f, "connection closed: {}", reason),
+      MessageNotSent(_, cause) => write!(f, "message not sent: {}", cause),
+      TransactionInConfirmMode => write!(f, "transactions can't be used on a channel in confirm mode"),
+      InvalidArguments(e) => write!(f, "invalid arguments: {}", e),
+      AlreadyConsumed => write!(f, "the value has already been taken"),
+      ConfirmTimeout => write!(f, "the publish wasn't confirmed in time"),
       __Nonexhaustive => write!(f, "lapin::error::ErrorKind::__Nonexhaustive: this should not be printed"),
     }
   }
@@ -75,6 +102,7 @@ impl Fail for ErrorKind {
     match self {
       ErrorKind::SerialisationError(cause) => Some(cause),
       ErrorKind::IOError(cause) => Some(cause),
+      ErrorKind::MessageNotSent(_, cause) => Some(&**cause),
       _ => None,
     }
   }
@@ -85,6 +113,15 @@ impl Error {
   pub fn kind(&self) -> &ErrorKind {
     self.inner.get_context()
   }
+
+  /// The payload of a message which couldn't be sent, so that it can be published again
+  pub fn unsent_payload(&self) -> Option<Bytes> {
+    if let ErrorKind::MessageNotSent(payload, _) = self.kind() {
+      Some(payload.clone())
+    } else {
+      None
+    }
+  }
 }
 
 impl Fail for Error {
@@ -109,6 +146,12 @@ impl From<ErrorKind> for Error {
   }
 }
 
+impl From<io::Error> for Error {
+  fn from(error: io::Error) -> Error {
+    ErrorKind::IOError(error).into()
+  }
+}
+
 impl From<Context<ErrorKind>> for Error {
   fn from(inner: Context<ErrorKind>) -> Error {
     Error { inner }
@@ -0,0 +1,164 @@
+use crate::types::{AMQPValue, FieldTable};
+
+/// The type of an exchange
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExchangeKind {
+  Direct,
+  Fanout,
+  Topic,
+  Headers,
+  /// An exchange type provided by a plugin, e.g. `x-delayed-message` or `x-consistent-hash`
+  Custom(String),
+}
+
+impl ExchangeKind {
+  /// The name of the exchange type, as sent to the server
+  pub fn kind(&self) -> &str {
+    match self {
+      ExchangeKind::Direct         => "direct",
+      ExchangeKind::Fanout         => "fanout",
+      ExchangeKind::Topic          => "topic",
+      ExchangeKind::Headers        => "headers",
+      ExchangeKind::Custom(custom) => custom,
+    }
+  }
+}
+
+/// The optional arguments of `exchange_declare`, with their RabbitMQ names and value types
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeArguments {
+  alternate_exchange: Option<String>,
+  delayed_type:       Option<ExchangeKind>,
+  hash_header:        Option<String>,
+  hash_property:      Option<String>,
+}
+
+impl ExchangeArguments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// `alternate-exchange`, where the messages this exchange can't route get published
+  pub fn alternate_exchange(mut self, exchange: &str) -> Self {
+    self.alternate_exchange = Some(exchange.into());
+    self
+  }
+
+  /// `x-delayed-type`, how an `x-delayed-message` exchange routes the messages once their delay elapsed
+  pub fn delayed_type(mut self, kind: ExchangeKind) -> Self {
+    self.delayed_type = Some(kind);
+    self
+  }
+
+  /// `hash-header`, the header an `x-consistent-hash` exchange hashes instead of the routing key
+  pub fn hash_header(mut self, header: &str) -> Self {
+    self.hash_header = Some(header.into());
+    self
+  }
+
+  /// `hash-property`, the property an `x-consistent-hash` exchange hashes instead of the routing key
+  pub fn hash_property(mut self, property: &str) -> Self {
+    self.hash_property = Some(property.into());
+    self
+  }
+
+  /// Turn the arguments into the `FieldTable` expected by `exchange_declare`
+  pub fn build(self) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    if let Some(exchange) = self.alternate_exchange {
+      arguments.insert("alternate-exchange".into(), AMQPValue::LongString(exchange.into()));
+    }
+    if let Some(kind) = self.delayed_type {
+      arguments.insert("x-delayed-type".into(), AMQPValue::LongString(kind.kind().into()));
+    }
+    if let Some(header) = self.hash_header {
+      arguments.insert("hash-header".into(), AMQPValue::LongString(header.into()));
+    }
+    if let Some(property) = self.hash_property {
+      arguments.insert("hash-property".into(), AMQPValue::LongString(property.into()));
+    }
+    arguments
+  }
+}
+
+/// Whether a message needs to match all the headers of a binding or any of them, sent as `x-match`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadersMatch {
+  All,
+  Any,
+}
+
+/// The arguments of `queue_bind` or `exchange_bind` to a headers exchange
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeadersBinding {
+  headers_match: HeadersMatch,
+  headers:       FieldTable,
+}
+
+impl HeadersBinding {
+  /// Route the messages having all the headers
+  pub fn all() -> Self {
+    Self::new(HeadersMatch::All)
+  }
+
+  /// Route the messages having any of the headers
+  pub fn any() -> Self {
+    Self::new(HeadersMatch::Any)
+  }
+
+  pub fn new(headers_match: HeadersMatch) -> Self {
+    Self { headers_match, headers: FieldTable::default() }
+  }
+
+  /// Match the header `name` with `value`
+  ///
+  /// The server ignores the headers whose name starts with `x-` when matching, and an `x-match`
+  /// header is replaced by the one given to `new` when calling `build`.
+  pub fn header(mut self, name: &str, value: AMQPValue) -> Self {
+    self.headers.insert(name.into(), value);
+    self
+  }
+
+  /// Turn the binding into the `FieldTable` expected by `queue_bind` or `exchange_bind`
+  pub fn build(self) -> FieldTable {
+    let mut arguments = self.headers;
+    let headers_match = match self.headers_match {
+      HeadersMatch::All => "all",
+      HeadersMatch::Any => "any",
+    };
+    arguments.insert("x-match".into(), AMQPValue::LongString(headers_match.into()));
+    arguments
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exchange_kinds() {
+    assert_eq!(ExchangeKind::Topic.kind(), "topic");
+    assert_eq!(ExchangeKind::Custom("x-consistent-hash".into()).kind(), "x-consistent-hash");
+  }
+
+  #[test]
+  fn delayed_exchange_arguments() {
+    let arguments = ExchangeArguments::new().alternate_exchange("unroutable").delayed_type(ExchangeKind::Direct).build();
+    assert_eq!(arguments.inner().get("alternate-exchange"), Some(&AMQPValue::LongString("unroutable".into())));
+    assert_eq!(arguments.inner().get("x-delayed-type"), Some(&AMQPValue::LongString("direct".into())));
+  }
+
+  #[test]
+  fn headers_binding() {
+    let arguments = HeadersBinding::any().header("format", AMQPValue::LongString("pdf".into())).header("size", AMQPValue::LongLongInt(42)).build();
+    assert_eq!(arguments.inner().get("x-match"), Some(&AMQPValue::LongString("any".into())));
+    assert_eq!(arguments.inner().get("format"), Some(&AMQPValue::LongString("pdf".into())));
+    assert_eq!(arguments.inner().get("size"), Some(&AMQPValue::LongLongInt(42)));
+  }
+
+  #[test]
+  fn headers_binding_keeps_its_match() {
+    let arguments = HeadersBinding::all().header("x-match", AMQPValue::LongString("any".into())).build();
+    assert_eq!(arguments.inner().get("x-match"), Some(&AMQPValue::LongString("all".into())));
+  }
+}
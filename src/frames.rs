@@ -1,20 +1,57 @@
 use log::trace;
 use amq_protocol::frame::AMQPFrame;
+use bytes::Bytes;
 use parking_lot::Mutex;
 
 use std::{
-  collections::{VecDeque, HashMap},
+  collections::{VecDeque, HashMap, HashSet},
   sync::Arc,
 };
 
 use crate::{
   channel::Reply,
+  connection_properties::OutboxWatermarks,
+  diagnostics::FramesDiagnostics,
+  error::{Error, ErrorKind},
   id_sequence::IdSequence,
   wait::{Wait, WaitHandle},
 };
 
 pub(crate) type SendId = u64;
 
+/// A frame waiting to be sent
+///
+/// Body frames are kept as slices of the published payload so that it never gets copied
+#[derive(Debug)]
+pub(crate) enum OutgoingFrame {
+  Frame(AMQPFrame),
+  Body(u16, Bytes),
+}
+
+impl From<AMQPFrame> for OutgoingFrame {
+  fn from(frame: AMQPFrame) -> Self {
+    OutgoingFrame::Frame(frame)
+  }
+}
+
+/// What happened to a frame handed over by `Frames::drain`
+pub(crate) enum Drained {
+  /// The frame has been serialized
+  Sent,
+  /// The frame is being written and will be marked as sent later on
+  Writing,
+  /// The frame couldn't be serialized yet and needs to be sent first next time on its channel
+  Retry(OutgoingFrame),
+}
+
+/// Where a frame was picked from, to put it back there if it can't be serialized yet
+#[derive(Clone, Copy, Debug)]
+enum Origin {
+  Priority,
+  Channel(u16),
+  LowPriority(u16),
+}
+
 #[derive(Clone, Debug)]
 pub(crate) enum Priority {
   LOW,
@@ -38,66 +75,200 @@ impl Frames {
     self.inner.lock().push(channel_id, priority, frame, expected_reply)
   }
 
-  pub(crate) fn retry(&self, send_id: SendId, frame: AMQPFrame) {
-    self.inner.lock().priority_frames.push_back((send_id, frame))
+  /// Push several frames at once, ensuring nothing else gets queued in between on this channel
+  ///
+  /// If the high watermark has been reached, the frames are kept aside until we get back
+  /// under the low watermark. The returned Wait completes once the last frame has been sent.
+  ///
+  /// If the frames get dropped before being sent, `payload` is handed back through the error.
+  pub(crate) fn push_frames(&self, channel_id: u16, priority: Priority, frames: Vec<OutgoingFrame>, payload: Option<Bytes>) -> Wait<()> {
+    let (wait, wait_handle) = Wait::new();
+    self.inner.lock().push_frames(channel_id, priority, frames, PendingSend { wait_handle, payload });
+    wait
+  }
+
+  /// Whether the high watermark has been reached and we're waiting to get back under the low one
+  pub(crate) fn is_full(&self) -> bool {
+    self.inner.lock().is_full()
+  }
+
+  pub(crate) fn set_watermarks(&self, watermarks: OutboxWatermarks) {
+    self.inner.lock().watermarks = watermarks;
+  }
+
+  /// Pause or resume sending the low priority frames of a channel, as requested by the server with `channel.flow`
+  pub(crate) fn set_flow(&self, channel_id: u16, flow: bool) {
+    let mut inner = self.inner.lock();
+    if flow {
+      inner.paused_channels.remove(&channel_id);
+    } else {
+      inner.paused_channels.insert(channel_id);
+    }
   }
 
-  pub(crate) fn pop(&self, flow: bool) -> Option<(SendId, AMQPFrame)> {
-    self.inner.lock().pop(flow)
+  /// Hand the frames to `f` one by one, until it cannot take more
+  ///
+  /// returns true if there are no frames left to send
+  pub(crate) fn drain<F: FnMut(SendId, OutgoingFrame) -> Result<Drained, Error>>(&self, f: F) -> Result<bool, Error> {
+    self.inner.lock().drain(f)
   }
 
   pub(crate) fn next_expected_reply(&self, channel_id: u16) -> Option<Reply> {
     self.inner.lock().expected_replies.get_mut(&channel_id).and_then(|replies| replies.pop_front())
   }
 
-  pub(crate) fn clear_expected_replies(&self, channel_id: u16) {
-    self.inner.lock().expected_replies.remove(&channel_id);
+  pub(crate) fn expected_replies(&self, channel_id: u16) -> Vec<&'static str> {
+    self.inner.lock().expected_replies.get(&channel_id).map(|replies| replies.iter().map(Reply::name).collect()).unwrap_or_default()
+  }
+
+  /// Fail the replies we're waiting for on this channel
+  pub(crate) fn fail_expected_replies<F: Fn() -> ErrorKind>(&self, channel_id: u16, error: F) {
+    let replies = self.inner.lock().expected_replies.remove(&channel_id);
+    for reply in replies.into_iter().flatten() {
+      reply.error(error().into());
+    }
   }
 
   pub(crate) fn mark_sent(&self, send_id: SendId) {
-    if let Some(send) = self.inner.lock().outbox.remove(&send_id) {
-      send.finish(());
+    self.inner.lock().mark_sent(send_id);
+  }
+
+  /// Drop the frames not sent yet on this channel, failing what was waiting for them
+  pub(crate) fn drop_channel_frames<F: Fn() -> ErrorKind>(&self, channel_id: u16, error: F) {
+    self.inner.lock().drop_channel_frames(channel_id, &error);
+  }
+
+  /// Drop all the frames not sent yet, failing what was waiting for them and for a reply
+  pub(crate) fn drop_pending<F: Fn() -> ErrorKind>(&self, error: F) {
+    let replies = self.inner.lock().drop_pending(&error);
+    for reply in replies {
+      reply.error(error().into());
     }
   }
 
-  pub(crate) fn drop_pending(&self) {
-    self.inner.lock().drop_pending();
+  pub(crate) fn diagnostics(&self) -> FramesDiagnostics {
+    let inner = self.inner.lock();
+    FramesDiagnostics {
+      priority_frames: inner.priority_frames.len(),
+      frames:          inner.channels.values().map(|channel| channel.frames.len()).sum(),
+      low_prio_frames: inner.channels.values().map(|channel| channel.low_prio_frames.len()).sum(),
+      pending_sends:   inner.outbox.len(),
+      queued_bytes:    inner.queued_bytes,
+      parked_frames:   inner.parked.iter().map(|parked| parked.frames.len()).sum(),
+      full:            inner.is_full(),
+      paused_channels: inner.paused_channels.len(),
+    }
   }
 }
 
 #[derive(Debug)]
 struct Inner {
-  priority_frames:  VecDeque<(SendId, AMQPFrame)>,
-  frames:           VecDeque<(SendId, AMQPFrame)>,
-  low_prio_frames:  VecDeque<(SendId, AMQPFrame)>,
+  priority_frames:  VecDeque<(SendId, OutgoingFrame)>,
+  channels:         HashMap<u16, ChannelFrames>,
+  /// The channels having frames to send, in the order they'll be picked in
+  ready_channels:   VecDeque<u16>,
   expected_replies: HashMap<u16, VecDeque<Reply>>,
-  outbox:           HashMap<SendId, WaitHandle<()>>,
+  outbox:           HashMap<SendId, PendingSend>,
   send_id:          IdSequence<SendId>,
+  watermarks:       OutboxWatermarks,
+  /// The size of the body frames in the channels queues
+  queued_bytes:     usize,
+  /// The number of frames in the channels queues
+  queued_frames:    usize,
+  full:             bool,
+  /// The frames waiting for the queues to get back under the low watermark
+  parked:           VecDeque<ParkedFrames>,
+  /// The channels on which the server asked us to stop publishing
+  paused_channels:  HashSet<u16>,
+}
+
+#[derive(Debug)]
+struct ParkedFrames {
+  channel_id: u16,
+  priority:   Priority,
+  frames:     Vec<OutgoingFrame>,
+  send:       PendingSend,
+}
+
+/// Something waiting for a frame to be sent
+#[derive(Debug)]
+struct PendingSend {
+  wait_handle: WaitHandle<()>,
+  /// The payload of the message being published, handed back if it doesn't get sent
+  payload:     Option<Bytes>,
+}
+
+impl PendingSend {
+  fn fail(self, error: ErrorKind) {
+    let error = match self.payload {
+      Some(payload) => ErrorKind::MessageNotSent(payload, Box::new(error)),
+      None          => error,
+    };
+    self.wait_handle.error(error.into());
+  }
 }
 
 impl Default for Inner {
   fn default() -> Self {
     Self {
       priority_frames:  VecDeque::default(),
-      frames:           VecDeque::default(),
-      low_prio_frames:  VecDeque::default(),
+      channels:         HashMap::default(),
+      ready_channels:   VecDeque::default(),
       expected_replies: HashMap::default(),
       outbox:           HashMap::default(),
       send_id:          IdSequence::new(false),
+      watermarks:       OutboxWatermarks::default(),
+      queued_bytes:     0,
+      queued_frames:    0,
+      full:             false,
+      parked:           VecDeque::default(),
+      paused_channels:  HashSet::default(),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct ChannelFrames {
+  frames:          VecDeque<(SendId, OutgoingFrame)>,
+  low_prio_frames: VecDeque<(SendId, OutgoingFrame)>,
+}
+
+impl ChannelFrames {
+  fn is_empty(&self) -> bool {
+    self.frames.is_empty() && self.low_prio_frames.is_empty()
+  }
+
+  /// Once the content of a message has started being sent, nothing else can be sent on the channel until it's complete
+  fn sending_content(&self) -> bool {
+    match self.low_prio_frames.front() {
+      Some((_, OutgoingFrame::Frame(AMQPFrame::Header(..)))) | Some((_, OutgoingFrame::Body(..))) => true,
+      _                                                                                      => false,
+    }
+  }
+
+  /// Returns whether the frame is a low priority one along with it
+  ///
+  /// The content being sent is completed even if the server paused the flow meanwhile, as its
+  /// reply to the `channel.flow` can only go out afterwards.
+  fn pop(&mut self, flow: bool) -> Option<(bool, (SendId, OutgoingFrame))> {
+    let sending_content = self.sending_content();
+    if !sending_content {
+      if let Some(frame) = self.frames.pop_front() {
+        return Some((false, frame));
+      }
+    }
+    if flow || sending_content {
+      self.low_prio_frames.pop_front().map(|frame| (true, frame))
+    } else {
+      None
     }
   }
 }
 
 impl Inner {
   fn push(&mut self, channel_id: u16, priority: Priority, frame: AMQPFrame, expected_reply: Option<Reply>) -> Wait<()> {
-    let send_id = if let Priority::CRITICAL = priority { 0 } else { self.send_id.next() };
-    match priority {
-      Priority::LOW      => self.low_prio_frames.push_back((send_id, frame)),
-      Priority::NORMAL   => self.frames.push_back((send_id, frame)),
-      Priority::CRITICAL => self.priority_frames.push_front((send_id, frame)),
-    }
     let (wait, wait_handle) = Wait::new();
-    self.outbox.insert(send_id, wait_handle);
+    self.enqueue(channel_id, priority, frame.into(), Some(PendingSend { wait_handle, payload: None }));
     if let Some(reply) = expected_reply {
       trace!("channel {} state is now waiting for {:?}", channel_id, reply);
       self.expected_replies.entry(channel_id).or_default().push_back(reply);
@@ -105,17 +276,341 @@ impl Inner {
     wait
   }
 
-  fn pop(&mut self, flow: bool) -> Option<(SendId, AMQPFrame)> {
-    self.priority_frames.pop_front().or_else(|| self.frames.pop_front()).or_else(|| if flow { self.low_prio_frames.pop_front() } else { None })
+  fn push_frames(&mut self, channel_id: u16, priority: Priority, frames: Vec<OutgoingFrame>, send: PendingSend) {
+    if self.full || !self.parked.is_empty() {
+      trace!("outbox is full, parking {} frames for channel {}", frames.len(), channel_id);
+      self.parked.push_back(ParkedFrames { channel_id, priority, frames, send });
+    } else {
+      self.enqueue_frames(channel_id, priority, frames, send);
+    }
   }
 
-  fn drop_pending(&mut self) {
+  fn enqueue_frames(&mut self, channel_id: u16, priority: Priority, frames: Vec<OutgoingFrame>, send: PendingSend) {
+    let mut send   = Some(send);
+    let mut frames = frames.into_iter().peekable();
+    while let Some(frame) = frames.next() {
+      let send = if frames.peek().is_none() { send.take() } else { None };
+      self.enqueue(channel_id, priority.clone(), frame, send);
+    }
+    if let Some(send) = send {
+      send.wait_handle.finish(());
+    }
+    if self.queued_bytes >= self.watermarks.high_bytes || self.queued_frames >= self.watermarks.high_frames {
+      self.full = true;
+    }
+  }
+
+  fn enqueue(&mut self, channel_id: u16, priority: Priority, frame: OutgoingFrame, send: Option<PendingSend>) {
+    let send_id = if let Priority::CRITICAL = priority { 0 } else { self.send_id.next() };
+    if let Priority::CRITICAL = priority {
+      self.priority_frames.push_front((send_id, frame));
+    } else {
+      self.queued_bytes  += frame_bytes(&frame);
+      self.queued_frames += 1;
+      match priority {
+        Priority::LOW => self.channel_frames(channel_id).low_prio_frames.push_back((send_id, frame)),
+        _             => self.channel_frames(channel_id).frames.push_back((send_id, frame)),
+      }
+    }
+    if let Some(send) = send {
+      self.outbox.insert(send_id, send);
+    }
+  }
+
+  fn is_full(&self) -> bool {
+    self.full || !self.parked.is_empty()
+  }
+
+  fn dequeued(&mut self, frame: &OutgoingFrame) {
+    self.queued_bytes  -= frame_bytes(frame);
+    self.queued_frames -= 1;
+    self.release_parked();
+  }
+
+  fn release_parked(&mut self) {
+    if self.full && self.queued_bytes <= self.watermarks.low_bytes && self.queued_frames <= self.watermarks.low_frames {
+      self.full = false;
+    }
+    while !self.full {
+      if let Some(parked) = self.parked.pop_front() {
+        self.enqueue_frames(parked.channel_id, parked.priority, parked.frames, parked.send);
+      } else {
+        break;
+      }
+    }
+  }
+
+  fn channel_frames(&mut self, channel_id: u16) -> &mut ChannelFrames {
+    if !self.channels.contains_key(&channel_id) {
+      self.ready_channels.push_back(channel_id);
+    }
+    self.channels.entry(channel_id).or_default()
+  }
+
+  /// Pick the next frame, going through the channels in a round-robin fashion
+  /// so that one channel sending a lot of frames doesn't starve the other ones
+  fn pop(&mut self) -> Option<(Origin, SendId, OutgoingFrame)> {
+    if let Some((send_id, frame)) = self.priority_frames.pop_front() {
+      return Some((Origin::Priority, send_id, frame));
+    }
+    for _ in 0..self.ready_channels.len() {
+      let channel_id = self.ready_channels.pop_front()?;
+      let flow       = !self.paused_channels.contains(&channel_id);
+      if let Some(channel) = self.channels.get_mut(&channel_id) {
+        let frame = channel.pop(flow);
+        if channel.is_empty() {
+          self.channels.remove(&channel_id);
+        } else {
+          self.ready_channels.push_back(channel_id);
+        }
+        if let Some((low_prio, (send_id, frame))) = frame {
+          self.dequeued(&frame);
+          let origin = if low_prio { Origin::LowPriority(channel_id) } else { Origin::Channel(channel_id) };
+          return Some((origin, send_id, frame));
+        }
+      }
+    }
+    None
+  }
+
+  /// Put a frame that couldn't be serialized back in front of its queue, so that it still
+  /// goes before the other frames of its channel and waits for the flow like them
+  fn requeue(&mut self, origin: Origin, send_id: SendId, frame: OutgoingFrame) {
+    let channel_id = match origin {
+      Origin::Priority                => return self.priority_frames.push_front((send_id, frame)),
+      Origin::Channel(channel_id)     => channel_id,
+      Origin::LowPriority(channel_id) => channel_id,
+    };
+    self.queued_bytes  += frame_bytes(&frame);
+    self.queued_frames += 1;
+    let channel = self.channel_frames(channel_id);
+    if let Origin::LowPriority(_) = origin {
+      channel.low_prio_frames.push_front((send_id, frame));
+    } else {
+      channel.frames.push_front((send_id, frame));
+    }
+  }
+
+  /// Hand as many frames as possible to `f` in one go, the lock being held only once
+  fn drain<F: FnMut(SendId, OutgoingFrame) -> Result<Drained, Error>>(&mut self, mut f: F) -> Result<bool, Error> {
+    while let Some((origin, send_id, frame)) = self.pop() {
+      match f(send_id, frame)? {
+        Drained::Sent         => self.mark_sent(send_id),
+        // The frames serialized after this one are written once it is
+        Drained::Writing      => {},
+        Drained::Retry(frame) => {
+          self.requeue(origin, send_id, frame);
+          return Ok(false);
+        },
+      }
+    }
+    Ok(true)
+  }
+
+  fn mark_sent(&mut self, send_id: SendId) {
+    if let Some(send) = self.outbox.remove(&send_id) {
+      send.wait_handle.finish(());
+    }
+  }
+
+  fn drop_channel_frames(&mut self, channel_id: u16, error: &dyn Fn() -> ErrorKind) {
+    self.paused_channels.remove(&channel_id);
+    if let Some(channel) = self.channels.remove(&channel_id) {
+      self.ready_channels.retain(|id| *id != channel_id);
+      for (send_id, frame) in channel.frames.into_iter().chain(channel.low_prio_frames) {
+        self.queued_bytes  -= frame_bytes(&frame);
+        self.queued_frames -= 1;
+        if let Some(send) = self.outbox.remove(&send_id) {
+          send.fail(error());
+        }
+      }
+    }
+    let (dropped, parked): (Vec<_>, Vec<_>) = self.parked.drain(..).partition(|parked| parked.channel_id == channel_id);
+    self.parked = VecDeque::from(parked);
+    for parked in dropped {
+      parked.send.fail(error());
+    }
+    self.release_parked();
+  }
+
+  /// Returns the replies we were waiting for, to be failed once the lock is released
+  fn drop_pending(&mut self, error: &dyn Fn() -> ErrorKind) -> Vec<Reply> {
     self.priority_frames.clear();
-    self.frames.clear();
-    self.low_prio_frames.clear();
-    self.expected_replies.clear();
-    for (_, wait_handle) in self.outbox.drain() {
-      wait_handle.finish(());
+    self.channels.clear();
+    self.ready_channels.clear();
+    self.paused_channels.clear();
+    self.queued_bytes  = 0;
+    self.queued_frames = 0;
+    self.full          = false;
+    for parked in self.parked.drain(..) {
+      parked.send.fail(error());
+    }
+    for (_, send) in self.outbox.drain() {
+      send.fail(error());
     }
+    self.expected_replies.drain().flat_map(|(_, replies)| replies).collect()
+  }
+}
+
+fn frame_bytes(frame: &OutgoingFrame) -> usize {
+  match frame {
+    OutgoingFrame::Body(_, payload) => payload.len(),
+    _                               => 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn body(channel_id: u16, size: usize) -> Vec<OutgoingFrame> {
+    vec![OutgoingFrame::Body(channel_id, Bytes::from(vec![0; size]))]
+  }
+
+  /// Body frames are identified by their channel, other frames by the channel in their Heartbeat
+  fn drain_channels(frames: &Frames) -> Vec<u16> {
+    let mut channels = Vec::new();
+    frames.drain(|_, frame| {
+      match frame {
+        OutgoingFrame::Body(channel_id, _)                     => channels.push(channel_id),
+        OutgoingFrame::Frame(AMQPFrame::Heartbeat(channel_id)) => channels.push(channel_id),
+        OutgoingFrame::Frame(_)                                => {},
+      }
+      Ok(Drained::Sent)
+    }).unwrap();
+    channels
+  }
+
+  #[test]
+  fn round_robin_between_channels() {
+    let frames = Frames::default();
+    for _ in 0..3 {
+      frames.push_frames(1, Priority::LOW, body(1, 0), None);
+    }
+    frames.push_frames(2, Priority::LOW, body(2, 0), None);
+    frames.push(3, Priority::NORMAL, AMQPFrame::Heartbeat(3), None);
+    assert_eq!(drain_channels(&frames), vec![1, 2, 3, 1, 1]);
+  }
+
+  #[test]
+  fn content_is_not_interleaved_on_a_channel() {
+    let frames = Frames::default();
+    let mut content = body(1, 0);
+    content.extend(body(1, 0));
+    frames.push_frames(1, Priority::LOW, content, None);
+    frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(4), None);
+    assert_eq!(drain_channels(&frames), vec![1, 1, 4]);
+  }
+
+  #[test]
+  fn low_priority_frames_wait_for_flow() {
+    let frames = Frames::default();
+    frames.set_flow(1, false);
+    frames.push(1, Priority::LOW, AMQPFrame::Heartbeat(1), None);
+    frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(3), None);
+    frames.push(2, Priority::LOW, AMQPFrame::Heartbeat(2), None);
+    assert_eq!(drain_channels(&frames), vec![3, 2]);
+    assert_eq!(frames.diagnostics().low_prio_frames, 1);
+    frames.set_flow(1, true);
+    assert_eq!(drain_channels(&frames), vec![1]);
+  }
+
+  #[test]
+  fn content_being_sent_is_completed_before_pausing() {
+    let frames      = Frames::default();
+    let mut content = body(1, 0);
+    content.extend(body(1, 0));
+    content.extend(body(1, 0));
+    frames.push_frames(1, Priority::LOW, content, None);
+    // The server pauses the flow once the first body frame got sent
+    let mut sent    = 0;
+    frames.drain(|_, frame| {
+      sent += 1;
+      if sent > 1 { Ok(Drained::Retry(frame)) } else { Ok(Drained::Sent) }
+    }).unwrap();
+    frames.set_flow(1, false);
+    // Stands for the channel.flow-ok, then the next publish
+    frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(4), None);
+    frames.push(1, Priority::LOW, AMQPFrame::Heartbeat(1), None);
+    assert_eq!(drain_channels(&frames), vec![1, 1, 4]);
+    frames.set_flow(1, true);
+    assert_eq!(drain_channels(&frames), vec![1]);
+  }
+
+  #[test]
+  fn publishing_stops_at_the_high_watermark() {
+    let frames = Frames::default();
+    frames.set_watermarks(OutboxWatermarks { high_bytes: 10, high_frames: 100, low_bytes: 4, low_frames: 100 });
+    frames.push_frames(1, Priority::LOW, body(1, 6), None);
+    assert!(!frames.is_full());
+    frames.push_frames(1, Priority::LOW, body(1, 6), None);
+    assert!(frames.is_full());
+    let parked = frames.push_frames(2, Priority::LOW, body(2, 6), None);
+    assert_eq!(frames.diagnostics().parked_frames, 1);
+
+    assert_eq!(drain_channels(&frames), vec![1, 1, 2]);
+    assert!(parked.try_wait().is_some());
+    assert!(!frames.is_full());
+  }
+
+  #[test]
+  fn dropped_frames_hand_the_payload_back() {
+    let frames  = Frames::default();
+    let payload = Bytes::from_static(b"payload");
+    let dropped = frames.push_frames(1, Priority::LOW, vec![OutgoingFrame::Body(1, payload.clone())], Some(payload.clone()));
+    let kept    = frames.push_frames(2, Priority::LOW, body(2, 0), None);
+    frames.drop_channel_frames(1, || ErrorKind::NotConnected);
+    assert_eq!(dropped.try_wait().unwrap().unwrap_err().unsent_payload(), Some(payload));
+    assert!(kept.try_wait().is_none());
+    assert_eq!(drain_channels(&frames), vec![2]);
+    assert_eq!(frames.diagnostics().queued_bytes, 0);
+  }
+
+  #[test]
+  fn retried_frames_stay_first_on_their_channel() {
+    let frames = Frames::default();
+    frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(1), None);
+    frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(2), None);
+    frames.push(3, Priority::NORMAL, AMQPFrame::Heartbeat(3), None);
+    let mut handed = 0;
+    assert!(!frames.drain(|_, frame| {
+      handed += 1;
+      Ok(Drained::Retry(frame))
+    }).unwrap());
+    assert_eq!(handed, 1);
+    assert_eq!(frames.diagnostics().frames, 3);
+    assert_eq!(drain_channels(&frames), vec![3, 1, 2]);
+  }
+
+  #[test]
+  fn retried_low_priority_frames_wait_for_flow() {
+    let frames = Frames::default();
+    frames.push(1, Priority::LOW, AMQPFrame::Heartbeat(1), None);
+    frames.drain(|_, frame| Ok(Drained::Retry(frame))).unwrap();
+    frames.set_flow(1, false);
+    frames.push(2, Priority::NORMAL, AMQPFrame::Heartbeat(2), None);
+    assert_eq!(drain_channels(&frames), vec![2]);
+    frames.set_flow(1, true);
+    assert_eq!(drain_channels(&frames), vec![1]);
+  }
+
+  #[test]
+  fn writing_frames_are_sent_once_marked() {
+    let frames      = Frames::default();
+    let sent        = frames.push(1, Priority::NORMAL, AMQPFrame::Heartbeat(1), None);
+    let next        = frames.push(2, Priority::NORMAL, AMQPFrame::Heartbeat(2), None);
+    let mut writing = None;
+    // Draining goes on after the frame being written
+    assert!(frames.drain(|send_id, _| {
+      if writing.is_some() {
+        return Ok(Drained::Sent);
+      }
+      writing = Some(send_id);
+      Ok(Drained::Writing)
+    }).unwrap());
+    assert!(sent.try_wait().is_none());
+    assert!(next.try_wait().is_some());
+    frames.mark_sent(writing.unwrap());
+    assert!(sent.try_wait().is_some());
   }
 }
@@ -22,6 +22,11 @@ impl<T: Default + Copy + AddAssign<T> + PartialEq<T> + PartialOrd<T> + From<u8>>
   pub(crate) fn set_max(&self, max: T) {
     self.inner.lock().set_max(max)
   }
+
+  /// Start numbering again from the beginning
+  pub(crate) fn reset(&self) {
+    self.inner.lock().id = T::default();
+  }
 }
 
 #[derive(Debug)]
@@ -1,13 +1,15 @@
-use amq_protocol::frame::{GenError, Offset, gen_frame, parse_frame};
+use amq_protocol::frame::{AMQPFrame, GenError};
+use bytes::Bytes;
 use log::{error, trace};
 use mio::{Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use parking_lot::Mutex;
 
 use std::{
-  io::{self, Read, Write},
+  cmp,
+  io::{self, IoSlice, Read, Write},
   sync::{
     Arc,
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
   },
   thread::{self, Builder as ThreadBuilder, JoinHandle},
   time::{Duration, Instant},
@@ -15,9 +17,14 @@ use std::{
 
 use crate::{
   buffer::Buffer,
+  close_reason::CloseReason,
+  codec::{AMQPCodec, FRAME_BODY, FRAME_END, FRAME_HEADER_SIZE},
   connection::Connection,
   connection_status::ConnectionState,
+  diagnostics::BufferDiagnostics,
   error::{Error, ErrorKind},
+  frames::{Drained, OutgoingFrame, SendId},
+  redacted_frame::RedactedFrame,
 };
 
 const SOCKET:   Token = Token(1);
@@ -26,15 +33,22 @@ const CONTINUE: Token = Token(3);
 
 const FRAMES_STORAGE: usize = 32;
 
-#[derive(Clone, Debug)]
+/// The body frames at least this large are written straight from their payload
+const MIN_DIRECT_BODY_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, Default)]
 pub(crate) struct IoLoopHandle {
-  handle: Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>,
+  handle:  Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>,
+  buffers: Arc<BuffersFill>,
 }
 
-impl Default for IoLoopHandle {
-  fn default() -> Self {
-    Self { handle: Arc::new(Mutex::new(None)) }
-  }
+/// The fill levels of the io loop buffers, kept in atomics as they're updated on every iteration
+#[derive(Debug, Default)]
+struct BuffersFill {
+  receive_capacity: AtomicUsize,
+  receive_data:     AtomicUsize,
+  send_capacity:    AtomicUsize,
+  send_data:        AtomicUsize,
 }
 
 impl IoLoopHandle {
@@ -48,6 +62,25 @@ impl IoLoopHandle {
     }
     Ok(())
   }
+
+  pub(crate) fn set_buffers_diagnostics(&self, receive_buffer: BufferDiagnostics, send_buffer: BufferDiagnostics) {
+    self.buffers.receive_capacity.store(receive_buffer.capacity, Ordering::Relaxed);
+    self.buffers.receive_data.store(receive_buffer.available_data, Ordering::Relaxed);
+    self.buffers.send_capacity.store(send_buffer.capacity, Ordering::Relaxed);
+    self.buffers.send_data.store(send_buffer.available_data, Ordering::Relaxed);
+  }
+
+  pub(crate) fn buffers_diagnostics(&self) -> (BufferDiagnostics, BufferDiagnostics) {
+    let receive_buffer = BufferDiagnostics {
+      capacity:       self.buffers.receive_capacity.load(Ordering::Relaxed),
+      available_data: self.buffers.receive_data.load(Ordering::Relaxed),
+    };
+    let send_buffer    = BufferDiagnostics {
+      capacity:       self.buffers.send_capacity.load(Ordering::Relaxed),
+      available_data: self.buffers.send_data.load(Ordering::Relaxed),
+    };
+    (receive_buffer, send_buffer)
+  }
 }
 
 #[derive(Debug, PartialEq)]
@@ -57,6 +90,21 @@ enum Status {
   Stop,
 }
 
+/// A body frame being written straight from its payload, `written` includes the frame end
+struct OutgoingBody {
+  send_id:   SendId,
+  payload:   Bytes,
+  written:   usize,
+  /// The bytes of the send buffer to write before the body, the following ones come after it
+  preceding: usize,
+}
+
+/// A body frame being read from the receive buffer chunk by chunk
+struct IncomingBody {
+  channel_id: u16,
+  remaining:  usize,
+}
+
 pub(crate) struct IoLoop<T> {
   connection:     Connection,
   socket:         T,
@@ -65,9 +113,12 @@ pub(crate) struct IoLoop<T> {
   registration:   Registration,
   set_readiness:  SetReadiness,
   hb_handle:      Option<JoinHandle<()>>,
+  codec:          AMQPCodec,
   frame_size:     usize,
   receive_buffer: Buffer,
   send_buffer:    Buffer,
+  incoming_body:  Option<IncomingBody>,
+  outgoing_body:  Option<OutgoingBody>,
   can_write:      bool,
   can_read:       bool,
   has_data:       bool,
@@ -76,7 +127,8 @@ pub(crate) struct IoLoop<T> {
 
 impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
   pub(crate) fn new(connection: Connection, socket: T) -> Result<Self, Error> {
-    let frame_size = std::cmp::max(8192, connection.configuration().frame_max() as usize);
+    let frame_size      = cmp::max(8192, connection.configuration().frame_max() as usize);
+    let max_buffer_size = connection.configuration().max_buffer_size();
     let (registration, set_readiness) = Registration::new2();
     let inner = Self {
       connection,
@@ -86,9 +138,12 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
       registration,
       set_readiness,
       hb_handle:      None,
+      codec:          AMQPCodec::new(),
       frame_size,
-      receive_buffer: Buffer::with_capacity(FRAMES_STORAGE * frame_size),
-      send_buffer:    Buffer::with_capacity(FRAMES_STORAGE * frame_size),
+      receive_buffer: Buffer::with_capacity(FRAMES_STORAGE.saturating_mul(frame_size), max_buffer_size),
+      send_buffer:    Buffer::with_capacity(FRAMES_STORAGE.saturating_mul(frame_size), max_buffer_size),
+      incoming_body:  None,
+      outgoing_body:  None,
       can_write:      false,
       can_read:       false,
       has_data:       false,
@@ -133,9 +188,10 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
   fn ensure_setup(&mut self) -> Result<(), Error> {
     if self.status != Status::Setup && self.connection.status().connected() {
       let frame_max = self.connection.configuration().frame_max() as usize;
-      self.frame_size = std::cmp::max(self.frame_size, frame_max);
-      self.receive_buffer.grow(FRAMES_STORAGE * self.frame_size);
-      self.send_buffer.grow(FRAMES_STORAGE * self.frame_size);
+      self.frame_size = cmp::max(self.frame_size, frame_max);
+      // Both buffers are bounded by the configured max_buffer_size, frames bigger than that are handled incrementally
+      self.receive_buffer.grow(FRAMES_STORAGE.saturating_mul(self.frame_size));
+      self.send_buffer.grow(FRAMES_STORAGE.saturating_mul(self.frame_size));
       let heartbeat = self.connection.configuration().heartbeat();
       if heartbeat != 0 {
         trace!("io_loop: start heartbeat");
@@ -148,11 +204,12 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
   }
 
   fn wants_to_write(&self) -> bool {
-    self.can_write && self.has_data
+    self.can_write && (self.has_data || self.outgoing_body.is_some() || self.send_buffer.available_data() > 0)
   }
 
   fn wants_to_read(&self) -> bool {
-    self.can_read
+    // Stop reading while too many deliveries are waiting to be consumed, we'll get woken up once they are
+    self.can_read && !self.connection.reading_paused()
   }
 
   fn should_continue(&self) -> bool {
@@ -164,8 +221,12 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
     self.connection.clone().set_io_loop(ThreadBuilder::new().name("io_loop".to_owned()).spawn(move || {
       let mut events = Events::with_capacity(1024);
       while self.should_continue() {
-        self.do_run(&mut events)?;
+        if let Err(err) = self.do_run(&mut events) {
+          self.connection.run_timers(true);
+          return Err(err);
+        }
       }
+      self.connection.run_timers(true);
       if let Some(hb_handle) = self.hb_handle.take() {
         hb_handle.thread().unpark();
         hb_handle.join().expect("heartbeat loop failed");
@@ -180,8 +241,9 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
     trace!("io_loop run");
     self.ensure_setup()?;
     trace!("io_loop poll");
-    self.poll.poll(events, None).map_err(ErrorKind::IOError)?;
+    self.poll.poll(events, self.connection.next_timeout()).map_err(ErrorKind::IOError)?;
     trace!("io_loop poll done");
+    self.connection.run_timers(false);
     for event in events.iter() {
       match event.token() {
         SOCKET    => {
@@ -213,12 +275,11 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
                 wait_handle.error(ErrorKind::ConnectionRefused.into());
                 self.status = Status::Stop;
               }
-              self.connection.set_error()?;
+              self.connection.set_error(CloseReason::from(&e))?;
               return Err(e);
             }
           }
         }
-        self.send_buffer.shift_unless_available(self.frame_size);
       }
       if self.connection.status().closed() {
         self.status = Status::Stop;
@@ -229,12 +290,11 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
             ErrorKind::IOError(e) if e.kind() == io::ErrorKind::WouldBlock => self.can_read = false,
             _ => {
               error!("error reading: {:?}", e);
-              self.connection.set_error()?;
+              self.connection.set_error(CloseReason::from(&e))?;
               return Err(e);
             }
           }
         }
-        self.receive_buffer.shift_unless_available(self.frame_size);
       }
       if self.can_parse() {
         self.parse()?;
@@ -248,6 +308,7 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
       }
     }
     trace!("io_loop do_run done; can_read={}, can_write={}, has_data={}, status={:?}", self.can_read, self.can_write, self.has_data, self.status);
+    self.connection.set_buffers_diagnostics(self.receive_buffer.diagnostics(), self.send_buffer.diagnostics());
     Ok(())
   }
 
@@ -258,10 +319,32 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
   fn write_to_stream(&mut self) -> Result<(), Error> {
     self.serialize()?;
 
-    self.socket.write(&self.send_buffer.data()).map(|sz| {
-      trace!("wrote {} bytes", sz);
-      self.send_buffer.consume(sz);
-    }).map_err(|e| ErrorKind::IOError(e).into())
+    let (head, tail) = self.send_buffer.data_slices();
+    let frame_end    = [FRAME_END];
+    let mut sz = if let Some(body) = self.outgoing_body.as_ref() {
+      let ([head, tail], [after_head, after_tail]) = split_slices(head, tail, body.preceding);
+      let payload   = &body.payload[cmp::min(body.written, body.payload.len())..];
+      let frame_end = &frame_end[body.written.saturating_sub(body.payload.len())..];
+      write_slices(&mut self.socket, &[head, tail, payload, frame_end, after_head, after_tail])
+    } else {
+      write_slices(&mut self.socket, &[head, tail])
+    }.map_err(ErrorKind::IOError)?;
+    trace!("wrote {} bytes", sz);
+
+    if let Some(mut body) = self.outgoing_body.take() {
+      let preceding   = self.send_buffer.consume(cmp::min(sz, body.preceding));
+      let from_body   = cmp::min(sz - preceding, body.payload.len() + 1 - body.written);
+      body.preceding -= preceding;
+      body.written   += from_body;
+      sz             -= preceding + from_body;
+      if body.written > body.payload.len() {
+        self.connection.mark_sent(body.send_id);
+      } else {
+        self.outgoing_body = Some(body);
+      }
+    }
+    self.send_buffer.consume(sz);
+    Ok(())
   }
 
   fn read_from_stream(&mut self) -> Result<(), Error> {
@@ -276,59 +359,269 @@ impl<T: Evented + Read + Write + Send + 'static> IoLoop<T> {
   }
 
   fn serialize(&mut self) -> Result<(), Error> {
-    if let Some((send_id, next_msg)) = self.connection.next_frame() {
-      trace!("will write to buffer: {:?}", next_msg);
-      match gen_frame(self.send_buffer.space(), &next_msg).map(|tup| tup.0) {
-        Ok(sz) => {
-          self.send_buffer.fill(sz);
-          self.connection.mark_sent(send_id);
-          Ok(())
+    let max_logged_body_size = self.connection.configuration().max_logged_body_size();
+    let codec                = &self.codec;
+    let send_buffer          = &mut self.send_buffer;
+    let outgoing_body        = &mut self.outgoing_body;
+    let drained = self.connection.drain_frames(|send_id, frame| {
+      let frame = match frame {
+        OutgoingFrame::Frame(frame)              => frame,
+        OutgoingFrame::Body(channel_id, payload) => {
+          trace!("will write to buffer: Body({}, {} bytes)", channel_id, payload.len());
+          let frame_size = payload.len() + FRAME_HEADER_SIZE + 1;
+          let direct     = payload.len() >= MIN_DIRECT_BODY_SIZE;
+          // Only one body can be written straight from its payload at a time
+          if send_buffer.available_space() < FRAME_HEADER_SIZE || (direct && outgoing_body.is_some()) || (!direct && send_buffer.available_space() < frame_size) {
+            return Ok(Drained::Retry(OutgoingFrame::Body(channel_id, payload)));
+          }
+          let mut header = [FRAME_BODY, 0, 0, 0, 0, 0, 0];
+          header[1..3].copy_from_slice(&channel_id.to_be_bytes());
+          header[3..7].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+          send_buffer.push(&header);
+          if !direct {
+            send_buffer.push(&payload);
+            send_buffer.push(&[FRAME_END]);
+            return Ok(Drained::Sent);
+          }
+          // Large bodies are written straight from their payload, without being copied to the send buffer
+          *outgoing_body = Some(OutgoingBody { send_id, payload, written: 0, preceding: send_buffer.available_data() });
+          return Ok(Drained::Writing);
         },
-        Err(e) => {
-          match e {
-            GenError::BufferTooSmall(_) => {
-              // Requeue msg
-              self.connection.requeue_frame(send_id, next_msg)?;
-              self.send_buffer.shift();
-              Ok(())
-            },
-            GenError::InvalidOffset | GenError::CustomError(_) | GenError::NotYetImplemented => {
-              error!("error generating frame: {:?}", e);
-              self.connection.set_error()?;
-              Err(ErrorKind::SerialisationError(e).into())
-            }
+      };
+      trace!("will write to buffer: {:?}", RedactedFrame::new(&frame, max_logged_body_size));
+      match codec.encode_frame(&frame, send_buffer.space()) {
+        Ok(Some(sz)) => {
+          send_buffer.fill(sz);
+          Ok(Drained::Sent)
+        },
+        Ok(None)     => {
+          // The frame doesn't fit in the whole buffer, try to make room for it
+          if send_buffer.available_data() == 0 && !send_buffer.grow(send_buffer.capacity().saturating_mul(2)) {
+            error!("frame is larger than the send buffer");
+            return Err(ErrorKind::SerialisationError(GenError::BufferTooSmall(send_buffer.capacity())).into());
           }
-        }
+          Ok(Drained::Retry(frame.into()))
+        },
+        Err(e)       => {
+          error!("error generating frame: {:?}", e);
+          Err(e)
+        },
       }
-    } else {
-      self.has_data = false;
-      Ok(())
+    });
+    match drained {
+      Ok(true)  => {
+        self.has_data = false;
+        Ok(())
+      },
+      Ok(false) => Ok(()),
+      Err(e)    => {
+        self.connection.set_error(CloseReason::from(&e))?;
+        Err(e)
+      },
     }
   }
 
   fn parse(&mut self) -> Result<(), Error> {
-    match parse_frame(self.receive_buffer.data()) {
-      Ok((i, f)) => {
-        let consumed = self.receive_buffer.data().offset(i);
+    if self.incoming_body.is_some() {
+      return self.parse_body_chunk();
+    }
+    match self.codec.decode_frame(self.receive_buffer.data()) {
+      Ok(Some((f, consumed))) => {
         self.receive_buffer.consume(consumed);
+        self.handle_frame(f)
+      },
+      Ok(None)                => self.handle_incomplete_frame(),
+      Err(error)              => {
+        error!("parse error: {:?}", error);
+        self.connection.set_error(CloseReason::from(&error))?;
+        Err(error)
+      },
+    }
+  }
 
-        if let Err(e) = self.connection.handle_frame(f) {
-          self.connection.set_error()?;
-          Err(e)
-        } else {
-          Ok(())
+  fn handle_frame(&mut self, f: AMQPFrame) -> Result<(), Error> {
+    if let Err(e) = self.connection.handle_frame(f) {
+      self.connection.set_error(CloseReason::from(&e))?;
+      Err(e)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn handle_incomplete_frame(&mut self) -> Result<(), Error> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    if self.receive_buffer.copy_data(&mut header) < FRAME_HEADER_SIZE {
+      // Wait for more data
+      return Ok(());
+    }
+    let channel_id = u16::from_be_bytes([header[1], header[2]]);
+    let size       = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    let frame_size = size.saturating_add(FRAME_HEADER_SIZE + 1);
+    if frame_size > self.receive_buffer.capacity() {
+      if header[0] == FRAME_BODY {
+        // Don't wait for the whole body frame to fit in the buffer, handle it chunk by chunk
+        self.receive_buffer.consume(FRAME_HEADER_SIZE);
+        self.incoming_body = Some(IncomingBody { channel_id, remaining: size });
+        return self.parse_body_chunk();
+      }
+      if !self.receive_buffer.grow(frame_size) || self.receive_buffer.capacity() < frame_size {
+        error!("frame of size {} is larger than the receive buffer", frame_size);
+        let error: Error = ErrorKind::ParsingError(format!("frame of size {} is larger than the receive buffer", frame_size)).into();
+        self.connection.set_error(CloseReason::from(&error))?;
+        return Err(error);
+      }
+    }
+    if !self.receive_buffer.is_contiguous() {
+      self.receive_buffer.make_contiguous();
+    }
+    Ok(())
+  }
+
+  fn parse_body_chunk(&mut self) -> Result<(), Error> {
+    if let Some(mut body) = self.incoming_body.take() {
+      if body.remaining > 0 {
+        let size = cmp::min(self.receive_buffer.data().len(), body.remaining);
+        if size > 0 {
+          let payload = self.receive_buffer.data()[..size].to_vec();
+          self.receive_buffer.consume(size);
+          body.remaining -= size;
+          let channel_id = body.channel_id;
+          self.incoming_body = Some(body);
+          return self.handle_frame(AMQPFrame::Body(channel_id, payload));
+        }
+      } else {
+        let mut frame_end = [0];
+        if self.receive_buffer.copy_data(&mut frame_end) == 1 {
+          self.receive_buffer.consume(1);
+          if frame_end[0] != FRAME_END {
+            error!("parse error: invalid frame end {}", frame_end[0]);
+            let error: Error = ErrorKind::ParsingError(format!("invalid frame end {}", frame_end[0])).into();
+            self.connection.set_error(CloseReason::from(&error))?;
+            return Err(error);
+          }
+          return Ok(());
+        }
+      }
+      self.incoming_body = Some(body);
+    }
+    Ok(())
+  }
+}
+
+/// Split the buffered data, given as its two parts, after its `at` first bytes
+fn split_slices<'a>(head: &'a [u8], tail: &'a [u8], at: usize) -> ([&'a [u8]; 2], [&'a [u8]; 2]) {
+  if at <= head.len() {
+    ([&head[..at], &[]], [&head[at..], tail])
+  } else {
+    let at = cmp::min(at - head.len(), tail.len());
+    ([head, &tail[..at]], [&[], &tail[at..]])
+  }
+}
+
+/// Write the slices with as few calls as possible until the socket can't take more
+///
+/// Neither mio's TcpStream nor the TLS streams implement `write_vectored`, whose default
+/// implementation only writes the first slice, so we keep going as long as whole slices got
+/// written. An error is only returned if nothing got written, we'll get it again on the next
+/// write otherwise.
+fn write_slices<W: Write>(socket: &mut W, slices: &[&[u8]]) -> io::Result<usize> {
+  let slices      = slices.iter().filter(|slice| !slice.is_empty()).cloned().collect::<Vec<_>>();
+  let mut written = 0;
+  let mut first   = 0;
+  while first < slices.len() {
+    let io_slices = slices[first..].iter().map(|slice| IoSlice::new(slice)).collect::<Vec<_>>();
+    match socket.write_vectored(&io_slices) {
+      Ok(0)      => break,
+      Ok(mut sz) => {
+        written += sz;
+        while first < slices.len() && sz >= slices[first].len() {
+          sz    -= slices[first].len();
+          first += 1;
+        }
+        if sz > 0 {
+          // A slice was only partly written, the socket is full
+          break;
         }
       },
-      Err(e) => {
-        if e.is_incomplete() {
-          self.receive_buffer.shift();
-          Ok(())
-        } else {
-          error!("parse error: {:?}", e);
-          self.connection.set_error()?;
-          Err(ErrorKind::ParsingError(format!("{:?}", e)).into())
+      Err(e)     => {
+        if written == 0 {
+          return Err(e);
         }
+        trace!("write interrupted after {} bytes: {:?}", written, e);
+        break;
+      },
+    }
+  }
+  Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Takes at most `room` bytes, then would block
+  struct Socket {
+    written:  Vec<u8>,
+    room:     usize,
+    vectored: bool,
+    calls:    usize,
+  }
+
+  impl Socket {
+    fn new(room: usize, vectored: bool) -> Self {
+      Self { written: Vec::new(), room, vectored, calls: 0 }
+    }
+  }
+
+  impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.calls += 1;
+      if self.room == 0 {
+        return Err(io::ErrorKind::WouldBlock.into());
+      }
+      let sz = cmp::min(self.room, buf.len());
+      self.written.extend_from_slice(&buf[..sz]);
+      self.room -= sz;
+      Ok(sz)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+      if !self.vectored {
+        // What the default implementation does
+        return self.write(bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &**buf));
       }
+      self.write(&bufs.iter().flat_map(|buf| buf.iter().cloned()).collect::<Vec<_>>())
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn write_all_the_slices() {
+    for vectored in &[false, true] {
+      let mut socket = Socket::new(100, *vectored);
+      assert_eq!(write_slices(&mut socket, &[&b"head"[..], &[], &b"payload"[..], &[FRAME_END]]).ok(), Some(12));
+      assert_eq!(&socket.written[..11], b"headpayload");
+      assert_eq!(socket.calls, if *vectored { 1 } else { 3 });
+    }
+  }
+
+  #[test]
+  fn stop_writing_when_the_socket_is_full() {
+    for vectored in &[false, true] {
+      let mut socket = Socket::new(6, *vectored);
+      assert_eq!(write_slices(&mut socket, &[&b"head"[..], &b"payload"[..]]).ok(), Some(6));
+      assert_eq!(&socket.written[..], b"headpa");
+      assert_eq!(write_slices(&mut socket, &[&b"yload"[..]]).map_err(|e| e.kind()), Err(io::ErrorKind::WouldBlock));
+    }
+  }
+
+  #[test]
+  fn split_the_buffered_data() {
+    assert_eq!(split_slices(b"head", b"tail", 2), ([&b"he"[..], &[]], [&b"ad"[..], &b"tail"[..]]));
+    assert_eq!(split_slices(b"head", b"tail", 6), ([&b"head"[..], &b"ta"[..]], [&[][..], &b"il"[..]]));
+    assert_eq!(split_slices(b"head", b"tail", 10), ([&b"head"[..], &b"tail"[..]], [&[][..], &[][..]]));
   }
 }
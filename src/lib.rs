@@ -57,37 +57,64 @@ pub use amq_protocol::{
   protocol::{self, BasicProperties},
   auth, tcp, types, uri,
 };
+pub use bytes::Bytes;
 
 pub use channel::{Channel, options};
+pub use channel_builder::ChannelBuilder;
 pub use channel_status::{ChannelState, ChannelStatus};
+pub use close_reason::CloseReason;
 pub use configuration::Configuration;
 pub use connection::{Connect, Connection};
-pub use connection_properties::ConnectionProperties;
+pub use connection_properties::{ConnectionProperties, OutboxWatermarks};
 pub use connection_status::{ConnectionState, ConnectionStatus};
 pub use consumer::{Consumer, ConsumerDelegate};
 pub use error::{Error, ErrorKind};
+pub use exchange::{ExchangeArguments, ExchangeKind, HeadersBinding, HeadersMatch};
+pub use publisher_confirm::PublisherConfirm;
 pub use queue::Queue;
+pub use queue_arguments::{Overflow, QueueArguments, QueueType};
+pub use returned_messages::ReturnedMessageDelegate;
 
+pub mod codec;
 pub mod confirmation;
+pub mod diagnostics;
 pub mod message;
 
+/// Internals exposed for the benchmarks, not covered by semver
+#[cfg(feature = "unstable")]
+#[doc(hidden)]
+pub mod unstable {
+  pub use crate::{acknowledgement::Acknowledgements, wait::{Wait, WaitHandle}};
+}
+
 mod acknowledgement;
 mod buffer;
 mod channel;
+mod channel_builder;
+mod channel_recovery;
 mod channel_status;
 mod channels;
+mod close_handler;
+mod close_reason;
 mod configuration;
 mod connection;
 mod connection_properties;
 mod connection_status;
 mod consumer;
+mod delivery_budget;
 mod error;
 mod error_handler;
+mod exchange;
 mod frames;
 mod id_sequence;
 mod io_loop;
+mod publisher_confirm;
 mod queue;
+mod queue_arguments;
 mod queues;
+mod redacted_frame;
 mod registration;
 mod returned_messages;
+mod timers;
+mod transaction;
 mod wait;
@@ -25,8 +25,17 @@ impl Delivery {
     }
   }
 
-  pub(crate) fn receive_content(&mut self, data: Vec<u8>) {
-    self.data.extend(data);
+  /// `remaining_size` is the size of the content not received yet, including `data`
+  pub(crate) fn receive_content(&mut self, data: Vec<u8>, remaining_size: usize) {
+    if self.data.is_empty() {
+      if data.len() == remaining_size {
+        // The whole content fits in a single frame, no need to copy it
+        self.data = data;
+        return;
+      }
+      self.data.reserve_exact(remaining_size);
+    }
+    self.data.extend_from_slice(&data);
   }
 }
 
@@ -0,0 +1,30 @@
+use crate::message::BasicReturnMessage;
+
+/// The outcome of a publish, as reported by the server when the channel is in confirm mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublisherConfirm {
+  /// The channel isn't in confirm mode, the message has been sent but the server won't tell us what happened to it
+  NotRequested,
+  /// The server took responsibility for the message, along with the message itself if it was unroutable and returned to us
+  Ack(Option<BasicReturnMessage>),
+  /// The server couldn't handle the message, along with the message itself if it was returned to us
+  Nack(Option<BasicReturnMessage>),
+}
+
+impl PublisherConfirm {
+  pub fn is_ack(&self) -> bool {
+    if let PublisherConfirm::Ack(_) = self { true } else { false }
+  }
+
+  pub fn is_nack(&self) -> bool {
+    if let PublisherConfirm::Nack(_) = self { true } else { false }
+  }
+
+  /// The message returned by the server, if any
+  pub fn take_message(self) -> Option<BasicReturnMessage> {
+    match self {
+      PublisherConfirm::Ack(message) | PublisherConfirm::Nack(message) => message,
+      PublisherConfirm::NotRequested                                   => None,
+    }
+  }
+}
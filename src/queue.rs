@@ -7,6 +7,7 @@ use std::{
 use crate::{
   BasicProperties,
   consumer::Consumer,
+  diagnostics::QueueDiagnostics,
   message::BasicGetMessage,
   types::ShortString,
   wait::WaitHandle,
@@ -38,6 +39,8 @@ pub(crate) struct QueueState {
   name:                ShortString,
   consumers:           HashMap<ShortString, Consumer>,
   current_get_message: Option<(BasicGetMessage, WaitHandle<Option<BasicGetMessage>>)>,
+  /// Whether the queue was declared on this channel, rather than only consumed from or got from
+  declared:            bool,
 }
 
 impl Queue {
@@ -53,6 +56,24 @@ impl Borrow<str> for Queue {
 }
 
 impl QueueState {
+  pub(crate) fn new(name: ShortString) -> Self {
+    Self {
+      name,
+      consumers:           HashMap::new(),
+      current_get_message: None,
+      declared:            false,
+    }
+  }
+
+  pub(crate) fn set_declared(&mut self) {
+    self.declared = true;
+  }
+
+  /// Nothing refers to a queue that wasn't declared on this channel once it has no consumer nor pending get
+  pub(crate) fn is_unused(&self) -> bool {
+    !self.declared && self.consumers.is_empty() && self.current_get_message.is_none()
+  }
+
   pub(crate) fn register_consumer(&mut self, consumer_tag: ShortString, consumer: Consumer) {
     self.consumers.insert(consumer_tag, consumer);
   }
@@ -69,6 +90,20 @@ impl QueueState {
     self.name.clone()
   }
 
+  pub(crate) fn consumer_tags(&self) -> impl Iterator<Item = &ShortString> {
+    self.consumers.keys()
+  }
+
+  pub(crate) fn diagnostics(&self) -> QueueDiagnostics {
+    let mut consumers = self.consumers.values().map(Consumer::diagnostics).collect::<Vec<_>>();
+    consumers.sort_by(|a, b| a.tag.cmp(&b.tag));
+    QueueDiagnostics {
+      name:        self.name.clone(),
+      consumers,
+      pending_get: self.current_get_message.is_some(),
+    }
+  }
+
   pub(crate) fn drop_prefetched_messages(&mut self) {
     for consumer in self.consumers.values() {
       consumer.drop_prefetched_messages();
@@ -85,9 +120,9 @@ impl QueueState {
     }
   }
 
-  pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>) {
+  pub(crate) fn receive_delivery_content(&mut self, payload: Vec<u8>, remaining_size: usize) {
     if let Some(delivery) = self.current_get_message.as_mut() {
-      delivery.0.delivery.receive_content(payload);
+      delivery.0.delivery.receive_content(payload, remaining_size);
     }
   }
 
@@ -100,10 +135,6 @@ impl QueueState {
 
 impl From<Queue> for QueueState {
   fn from(queue: Queue) -> Self {
-    Self {
-      name:                queue.name,
-      consumers:           HashMap::new(),
-      current_get_message: None,
-    }
+    Self::new(queue.name)
   }
 }
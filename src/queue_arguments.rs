@@ -0,0 +1,313 @@
+use crate::{
+  error::{Error, ErrorKind},
+  types::{AMQPValue, Boolean, FieldTable, LongLongInt, LongUInt, ShortShortUInt},
+};
+
+/// The kind of queue to declare, sent as `x-queue-type`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueType {
+  Classic,
+  Quorum,
+  Stream,
+}
+
+impl QueueType {
+  fn name(self) -> &'static str {
+    match self {
+      QueueType::Classic => "classic",
+      QueueType::Quorum  => "quorum",
+      QueueType::Stream  => "stream",
+    }
+  }
+}
+
+/// What happens once a queue reaches its maximum length, sent as `x-overflow`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+  /// Drop or dead-letter the oldest messages
+  DropHead,
+  /// Refuse the new messages
+  RejectPublish,
+  /// Refuse the new messages and dead-letter them
+  RejectPublishDlx,
+}
+
+impl Overflow {
+  fn name(self) -> &'static str {
+    match self {
+      Overflow::DropHead         => "drop-head",
+      Overflow::RejectPublish    => "reject-publish",
+      Overflow::RejectPublishDlx => "reject-publish-dlx",
+    }
+  }
+}
+
+/// The optional arguments of `queue_declare`, with their RabbitMQ names and value types
+///
+/// `build` checks that the arguments make sense together and for the queue type, so that
+/// mistakes are caught before the server closes the channel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueArguments {
+  queue_type:                    Option<QueueType>,
+  message_ttl:                   Option<LongUInt>,
+  expires:                       Option<LongUInt>,
+  dead_letter_exchange:          Option<String>,
+  dead_letter_routing_key:       Option<String>,
+  max_length:                    Option<LongLongInt>,
+  max_length_bytes:              Option<LongLongInt>,
+  overflow:                      Option<Overflow>,
+  max_priority:                  Option<ShortShortUInt>,
+  single_active_consumer:        Option<Boolean>,
+  delivery_limit:                Option<LongUInt>,
+  max_age:                       Option<String>,
+  stream_max_segment_size_bytes: Option<LongLongInt>,
+}
+
+impl QueueArguments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// `x-queue-type`
+  pub fn queue_type(mut self, queue_type: QueueType) -> Self {
+    self.queue_type = Some(queue_type);
+    self
+  }
+
+  /// `x-message-ttl`, in milliseconds
+  pub fn message_ttl(mut self, message_ttl: LongUInt) -> Self {
+    self.message_ttl = Some(message_ttl);
+    self
+  }
+
+  /// `x-expires`, how long the queue can stay unused before it gets deleted, in milliseconds
+  pub fn expires(mut self, expires: LongUInt) -> Self {
+    self.expires = Some(expires);
+    self
+  }
+
+  /// `x-dead-letter-exchange`
+  pub fn dead_letter_exchange(mut self, exchange: &str) -> Self {
+    self.dead_letter_exchange = Some(exchange.into());
+    self
+  }
+
+  /// `x-dead-letter-routing-key`, requires a dead letter exchange
+  pub fn dead_letter_routing_key(mut self, routing_key: &str) -> Self {
+    self.dead_letter_routing_key = Some(routing_key.into());
+    self
+  }
+
+  /// `x-max-length`, in messages
+  pub fn max_length(mut self, max_length: LongLongInt) -> Self {
+    self.max_length = Some(max_length);
+    self
+  }
+
+  /// `x-max-length-bytes`
+  pub fn max_length_bytes(mut self, max_length_bytes: LongLongInt) -> Self {
+    self.max_length_bytes = Some(max_length_bytes);
+    self
+  }
+
+  /// `x-overflow`
+  pub fn overflow(mut self, overflow: Overflow) -> Self {
+    self.overflow = Some(overflow);
+    self
+  }
+
+  /// `x-max-priority`, classic queues only
+  pub fn max_priority(mut self, max_priority: ShortShortUInt) -> Self {
+    self.max_priority = Some(max_priority);
+    self
+  }
+
+  /// `x-single-active-consumer`
+  pub fn single_active_consumer(mut self, single_active_consumer: Boolean) -> Self {
+    self.single_active_consumer = Some(single_active_consumer);
+    self
+  }
+
+  /// `x-delivery-limit`, quorum queues only
+  pub fn delivery_limit(mut self, delivery_limit: LongUInt) -> Self {
+    self.delivery_limit = Some(delivery_limit);
+    self
+  }
+
+  /// `x-max-age`, e.g. `"7D"` or `"12h"`, stream queues only
+  pub fn max_age(mut self, max_age: &str) -> Self {
+    self.max_age = Some(max_age.into());
+    self
+  }
+
+  /// `x-stream-max-segment-size-bytes`, stream queues only
+  pub fn stream_max_segment_size_bytes(mut self, size: LongLongInt) -> Self {
+    self.stream_max_segment_size_bytes = Some(size);
+    self
+  }
+
+  /// Check the arguments and turn them into the `FieldTable` expected by `queue_declare`
+  pub fn build(self) -> Result<FieldTable, Error> {
+    self.validate()?;
+
+    let mut arguments = FieldTable::default();
+    if let Some(queue_type) = self.queue_type {
+      arguments.insert("x-queue-type".into(), AMQPValue::LongString(queue_type.name().into()));
+    }
+    if let Some(message_ttl) = self.message_ttl {
+      arguments.insert("x-message-ttl".into(), AMQPValue::LongUInt(message_ttl));
+    }
+    if let Some(expires) = self.expires {
+      arguments.insert("x-expires".into(), AMQPValue::LongUInt(expires));
+    }
+    if let Some(exchange) = self.dead_letter_exchange {
+      arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(exchange.into()));
+    }
+    if let Some(routing_key) = self.dead_letter_routing_key {
+      arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(routing_key.into()));
+    }
+    if let Some(max_length) = self.max_length {
+      arguments.insert("x-max-length".into(), AMQPValue::LongLongInt(max_length));
+    }
+    if let Some(max_length_bytes) = self.max_length_bytes {
+      arguments.insert("x-max-length-bytes".into(), AMQPValue::LongLongInt(max_length_bytes));
+    }
+    if let Some(overflow) = self.overflow {
+      arguments.insert("x-overflow".into(), AMQPValue::LongString(overflow.name().into()));
+    }
+    if let Some(max_priority) = self.max_priority {
+      arguments.insert("x-max-priority".into(), AMQPValue::ShortShortUInt(max_priority));
+    }
+    if let Some(single_active_consumer) = self.single_active_consumer {
+      arguments.insert("x-single-active-consumer".into(), AMQPValue::Boolean(single_active_consumer));
+    }
+    if let Some(delivery_limit) = self.delivery_limit {
+      arguments.insert("x-delivery-limit".into(), AMQPValue::LongUInt(delivery_limit));
+    }
+    if let Some(max_age) = self.max_age {
+      arguments.insert("x-max-age".into(), AMQPValue::LongString(max_age.into()));
+    }
+    if let Some(size) = self.stream_max_segment_size_bytes {
+      arguments.insert("x-stream-max-segment-size-bytes".into(), AMQPValue::LongLongInt(size));
+    }
+    Ok(arguments)
+  }
+
+  fn validate(&self) -> Result<(), Error> {
+    if self.dead_letter_routing_key.is_some() && self.dead_letter_exchange.is_none() {
+      return invalid("x-dead-letter-routing-key requires x-dead-letter-exchange");
+    }
+    if self.max_length.map_or(false, |max_length| max_length < 0) || self.max_length_bytes.map_or(false, |max_length_bytes| max_length_bytes < 0) {
+      return invalid("the maximum length of a queue can't be negative");
+    }
+    if self.max_priority == Some(0) {
+      return invalid("x-max-priority must be between 1 and 255");
+    }
+
+    let queue_type = self.queue_type.unwrap_or(QueueType::Classic);
+    let forbidden  = match queue_type {
+      QueueType::Classic => vec![
+        ("x-delivery-limit", self.delivery_limit.is_some()),
+        ("x-max-age", self.max_age.is_some()),
+        ("x-stream-max-segment-size-bytes", self.stream_max_segment_size_bytes.is_some()),
+      ],
+      QueueType::Quorum  => vec![
+        ("x-max-priority", self.max_priority.is_some()),
+        ("x-overflow=reject-publish-dlx", self.overflow == Some(Overflow::RejectPublishDlx)),
+        ("x-max-age", self.max_age.is_some()),
+        ("x-stream-max-segment-size-bytes", self.stream_max_segment_size_bytes.is_some()),
+      ],
+      QueueType::Stream  => vec![
+        ("x-message-ttl", self.message_ttl.is_some()),
+        ("x-expires", self.expires.is_some()),
+        ("x-dead-letter-exchange", self.dead_letter_exchange.is_some()),
+        ("x-max-length", self.max_length.is_some()),
+        ("x-overflow", self.overflow.is_some()),
+        ("x-max-priority", self.max_priority.is_some()),
+        ("x-single-active-consumer", self.single_active_consumer.is_some()),
+        ("x-delivery-limit", self.delivery_limit.is_some()),
+      ],
+    };
+    if let Some((argument, _)) = forbidden.into_iter().find(|(_, set)| *set) {
+      return invalid(&format!("{} can't be used with {} queues", argument, queue_type.name()));
+    }
+    Ok(())
+  }
+}
+
+fn invalid(message: &str) -> Result<(), Error> {
+  Err(ErrorKind::InvalidArguments(message.into()).into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_invalid(arguments: QueueArguments) {
+    match arguments.build() {
+      Err(error) => match error.kind() {
+        ErrorKind::InvalidArguments(_) => {},
+        kind                           => panic!("unexpected error: {:?}", kind),
+      },
+      Ok(arguments) => panic!("the arguments should have been rejected: {:?}", arguments),
+    }
+  }
+
+  #[test]
+  fn classic_queue_arguments() {
+    let arguments = QueueArguments::new()
+      .message_ttl(60_000)
+      .dead_letter_exchange("dlx")
+      .dead_letter_routing_key("dead")
+      .max_length(1000)
+      .overflow(Overflow::RejectPublish)
+      .max_priority(10)
+      .build()
+      .unwrap();
+    assert_eq!(arguments.inner().get("x-message-ttl"), Some(&AMQPValue::LongUInt(60_000)));
+    assert_eq!(arguments.inner().get("x-dead-letter-exchange"), Some(&AMQPValue::LongString("dlx".into())));
+    assert_eq!(arguments.inner().get("x-dead-letter-routing-key"), Some(&AMQPValue::LongString("dead".into())));
+    assert_eq!(arguments.inner().get("x-max-length"), Some(&AMQPValue::LongLongInt(1000)));
+    assert_eq!(arguments.inner().get("x-overflow"), Some(&AMQPValue::LongString("reject-publish".into())));
+    assert_eq!(arguments.inner().get("x-max-priority"), Some(&AMQPValue::ShortShortUInt(10)));
+    assert_eq!(arguments.inner().get("x-queue-type"), None);
+  }
+
+  #[test]
+  fn quorum_queue_arguments() {
+    let arguments = QueueArguments::new()
+      .queue_type(QueueType::Quorum)
+      .delivery_limit(5)
+      .single_active_consumer(true)
+      .build()
+      .unwrap();
+    assert_eq!(arguments.inner().get("x-queue-type"), Some(&AMQPValue::LongString("quorum".into())));
+    assert_eq!(arguments.inner().get("x-delivery-limit"), Some(&AMQPValue::LongUInt(5)));
+    assert_eq!(arguments.inner().get("x-single-active-consumer"), Some(&AMQPValue::Boolean(true)));
+  }
+
+  #[test]
+  fn stream_queue_arguments() {
+    let arguments = QueueArguments::new()
+      .queue_type(QueueType::Stream)
+      .max_length_bytes(20_000_000_000)
+      .max_age("7D")
+      .build()
+      .unwrap();
+    assert_eq!(arguments.inner().get("x-queue-type"), Some(&AMQPValue::LongString("stream".into())));
+    assert_eq!(arguments.inner().get("x-max-length-bytes"), Some(&AMQPValue::LongLongInt(20_000_000_000)));
+    assert_eq!(arguments.inner().get("x-max-age"), Some(&AMQPValue::LongString("7D".into())));
+  }
+
+  #[test]
+  fn incompatible_arguments_are_rejected() {
+    assert_invalid(QueueArguments::new().dead_letter_routing_key("dead"));
+    assert_invalid(QueueArguments::new().max_length(-1));
+    assert_invalid(QueueArguments::new().max_priority(0));
+    assert_invalid(QueueArguments::new().delivery_limit(5));
+    assert_invalid(QueueArguments::new().queue_type(QueueType::Quorum).max_priority(10));
+    assert_invalid(QueueArguments::new().queue_type(QueueType::Quorum).overflow(Overflow::RejectPublishDlx));
+    assert_invalid(QueueArguments::new().queue_type(QueueType::Stream).message_ttl(1000));
+    assert_invalid(QueueArguments::new().queue_type(QueueType::Stream).dead_letter_exchange("dlx"));
+  }
+}
@@ -7,7 +7,9 @@ use std::{
 
 use crate::{
   BasicProperties,
+  acknowledgement::DeliveryTag,
   consumer::Consumer,
+  diagnostics::QueueDiagnostics,
   queue::QueueState,
   message::{BasicGetMessage, Delivery},
   types::ShortString,
@@ -16,62 +18,110 @@ use crate::{
 
 #[derive(Clone, Debug, Default)]
 pub(crate) struct Queues {
-  queues: Arc<Mutex<HashMap<ShortString, QueueState>>>,
+  inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  queues:    HashMap<ShortString, QueueState>,
+  /// The queue each consumer is consuming from, to find consumers without going through all the queues
+  consumers: HashMap<ShortString, ShortString>,
+}
+
+impl Inner {
+  fn queue(&mut self, queue: &str) -> &mut QueueState {
+    self.queues.entry(queue.into()).or_insert_with(|| QueueState::new(queue.into()))
+  }
+
+  /// Forget the queues created on demand by basic.get or basic.consume once they're done with
+  fn remove_if_unused(&mut self, queue: &str) {
+    if self.queues.get(queue).map_or(false, QueueState::is_unused) {
+      self.queues.remove(queue);
+    }
+  }
 }
 
 impl Queues {
   pub(crate) fn register(&self, queue: QueueState) {
-    self.queues.lock().insert(queue.name(), queue);
+    let mut inner = self.inner.lock();
+    let name      = queue.name();
+    // Keep the existing consumers if the queue gets declared again
+    if !inner.queues.contains_key(&name) {
+      for consumer_tag in queue.consumer_tags() {
+        inner.consumers.insert(consumer_tag.clone(), name.clone());
+      }
+      inner.queues.insert(name.clone(), queue);
+    }
+    if let Some(queue) = inner.queues.get_mut(&name) {
+      queue.set_declared();
+    }
   }
 
   pub(crate) fn deregister(&self, queue: &str) {
-    self.queues.lock().remove(queue);
+    let mut inner = self.inner.lock();
+    if inner.queues.remove(queue).is_some() {
+      inner.consumers.retain(|_, consumer_queue| consumer_queue.as_str() != queue);
+    }
   }
 
+  /// The queue doesn't need to have been declared on this channel
   pub(crate) fn register_consumer(&self, queue: &str, consumer_tag: ShortString, consumer: Consumer) {
-    if let Some(queue) = self.queues.lock().get_mut(queue) {
-      queue.register_consumer(consumer_tag, consumer);
-    }
+    let mut inner = self.inner.lock();
+    inner.queue(queue).register_consumer(consumer_tag.clone(), consumer);
+    inner.consumers.insert(consumer_tag, queue.into());
+  }
+
+  pub(crate) fn get_consumer(&self, consumer_tag: &str) -> Option<Consumer> {
+    let mut inner  = self.inner.lock();
+    let queue_name = inner.consumers.get(consumer_tag)?.clone();
+    inner.queues.get_mut(&queue_name)?.get_consumer(consumer_tag).cloned()
   }
 
   pub(crate) fn deregister_consumer(&self, consumer_tag: &str) {
-    for queue in self.queues.lock().values_mut() {
-      if let Some(consumer) = queue.deregister_consumer(consumer_tag) {
+    let mut inner = self.inner.lock();
+    if let Some(queue_name) = inner.consumers.remove(consumer_tag) {
+      if let Some(consumer) = inner.queues.get_mut(&queue_name).and_then(|queue| queue.deregister_consumer(consumer_tag)) {
         consumer.cancel();
       }
+      inner.remove_if_unused(queue_name.as_str());
     }
   }
 
+  pub(crate) fn diagnostics(&self) -> Vec<QueueDiagnostics> {
+    let mut queues = self.inner.lock().queues.values().map(QueueState::diagnostics).collect::<Vec<_>>();
+    queues.sort_by(|a, b| a.name.cmp(&b.name));
+    queues
+  }
+
   pub(crate) fn drop_prefetched_messages(&self) {
-    for queue in self.queues.lock().values_mut() {
+    for queue in self.inner.lock().queues.values_mut() {
       queue.drop_prefetched_messages();
     }
   }
 
   pub(crate) fn start_consumer_delivery(&self, consumer_tag: &str, message: Delivery) -> Option<ShortString> {
-    for queue in self.queues.lock().values_mut() {
-      if let Some(consumer) = queue.get_consumer(consumer_tag) {
-        consumer.start_new_delivery(message);
-        return Some(queue.name());
-      }
-    }
-    None
+    let mut inner = self.inner.lock();
+    let queue_name = inner.consumers.get(consumer_tag)?.clone();
+    let consumer   = inner.queues.get_mut(&queue_name)?.get_consumer(consumer_tag)?;
+    consumer.start_new_delivery(message);
+    Some(queue_name)
   }
 
   pub(crate) fn start_basic_get_delivery(&self, queue: &str, message: BasicGetMessage, wait_handle: WaitHandle<Option<BasicGetMessage>>) {
-    if let Some(queue) = self.queues.lock().get_mut(queue) {
-      queue.start_new_delivery(message, wait_handle);
-    }
+    self.inner.lock().queue(queue).start_new_delivery(message, wait_handle);
   }
 
-  pub(crate) fn handle_content_header_frame(&self, queue: &str, consumer_tag: Option<ShortString>, size: u64, properties: BasicProperties) {
-    if let Some(queue) = self.queues.lock().get_mut(queue) {
+  /// Returns the tag of the delivery if its consumer was abandoned, for it to be nacked
+  pub(crate) fn handle_content_header_frame(&self, queue_name: &str, consumer_tag: Option<ShortString>, size: u64, properties: BasicProperties) -> Option<DeliveryTag> {
+    let mut inner     = self.inner.lock();
+    let mut abandoned = None;
+    if let Some(queue) = inner.queues.get_mut(queue_name) {
       match consumer_tag {
         Some(consumer_tag) => {
           if let Some(consumer) = queue.get_consumer(&consumer_tag) {
             consumer.set_delivery_properties(properties);
             if size == 0 {
-              consumer.new_delivery_complete();
+              abandoned = consumer.new_delivery_complete();
             }
           }
         },
@@ -83,26 +133,90 @@ impl Queues {
         },
       }
     }
+    inner.remove_if_unused(queue_name);
+    abandoned
   }
 
-  pub(crate) fn handle_body_frame(&self, queue: &str, consumer_tag: Option<ShortString>, remaining_size: usize, payload_size: usize, payload: Vec<u8>) {
-    if let Some(queue) = self.queues.lock().get_mut(queue) {
+  /// Returns the tag of the delivery if its consumer was abandoned, for it to be nacked
+  pub(crate) fn handle_body_frame(&self, queue_name: &str, consumer_tag: Option<ShortString>, remaining_size: usize, payload_size: usize, payload: Vec<u8>) -> Option<DeliveryTag> {
+    let mut inner     = self.inner.lock();
+    let mut abandoned = None;
+    if let Some(queue) = inner.queues.get_mut(queue_name) {
       match consumer_tag {
         Some(consumer_tag) => {
           if let Some(consumer) = queue.get_consumer(&consumer_tag) {
-            consumer.receive_delivery_content(payload);
+            consumer.receive_delivery_content(payload, remaining_size);
             if remaining_size == payload_size {
-              consumer.new_delivery_complete();
+              abandoned = consumer.new_delivery_complete();
             }
           }
         },
         None               => {
-          queue.receive_delivery_content(payload);
+          queue.receive_delivery_content(payload, remaining_size);
           if remaining_size == payload_size {
             queue.new_delivery_complete();
           }
         },
       }
     }
+    inner.remove_if_unused(queue_name);
+    abandoned
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::{delivery_budget::DeliveryBudget, registration::Registration, wait::Wait};
+
+  fn consumer(consumer_tag: &str) -> Consumer {
+    Consumer::new(consumer_tag.into(), false, DeliveryBudget::new(Registration::default()))
+  }
+
+  #[test]
+  fn consumers_are_found_by_tag() {
+    let queues = Queues::default();
+    queues.register(QueueState::new("queue".into()));
+    queues.register_consumer("queue", "first".into(), consumer("first"));
+    queues.register_consumer("queue", "second".into(), consumer("second"));
+    assert_eq!(queues.get_consumer("first").unwrap().diagnostics().tag.as_str(), "first");
+    assert_eq!(queues.start_consumer_delivery("second", Delivery::new(1, "".into(), "queue".into(), false)), Some("queue".into()));
+    queues.deregister_consumer("first");
+    assert!(queues.get_consumer("first").is_none());
+    assert!(queues.get_consumer("second").is_some());
+    queues.deregister("queue");
+    assert!(queues.get_consumer("second").is_none());
+  }
+
+  #[test]
+  fn redeclaring_a_queue_keeps_its_consumers() {
+    let queues = Queues::default();
+    queues.register(QueueState::new("queue".into()));
+    queues.register_consumer("queue", "consumer".into(), consumer("consumer"));
+    queues.register(QueueState::new("queue".into()));
+    assert!(queues.get_consumer("consumer").is_some());
+    let diagnostics = queues.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].consumers.len(), 1);
+  }
+
+  #[test]
+  fn undeclared_queues_are_forgotten_once_unused() {
+    let queues = Queues::default();
+    queues.register(QueueState::new("declared".into()));
+    queues.register_consumer("declared", "first".into(), consumer("first"));
+    queues.register_consumer("consumed", "second".into(), consumer("second"));
+    assert_eq!(queues.diagnostics().len(), 2);
+    queues.deregister_consumer("first");
+    queues.deregister_consumer("second");
+    assert_eq!(queues.diagnostics().iter().map(|queue| queue.name.as_str()).collect::<Vec<_>>(), vec!["declared"]);
+
+    let (get, wait_handle) = Wait::new();
+    queues.start_basic_get_delivery("got", BasicGetMessage::new(1, "".into(), "got".into(), false, 0), wait_handle);
+    assert_eq!(queues.diagnostics().len(), 2);
+    queues.handle_content_header_frame("got", None, 0, BasicProperties::default());
+    assert!(get.try_wait().unwrap().unwrap().is_some());
+    assert_eq!(queues.diagnostics().len(), 1);
   }
 }
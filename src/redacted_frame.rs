@@ -0,0 +1,75 @@
+use amq_protocol::{
+  frame::AMQPFrame,
+  protocol::{AMQPClass, connection},
+};
+
+use std::fmt;
+
+const REDACTED: &str = "<redacted>";
+
+/// Formats an `AMQPFrame` for logging purpose, masking the SASL responses
+/// and truncating the bodies to `max_body_size` bytes.
+pub(crate) struct RedactedFrame<'a> {
+  frame:         &'a AMQPFrame,
+  max_body_size: usize,
+}
+
+impl<'a> RedactedFrame<'a> {
+  pub(crate) fn new(frame: &'a AMQPFrame, max_body_size: usize) -> Self {
+    Self { frame, max_body_size }
+  }
+}
+
+impl fmt::Debug for RedactedFrame<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.frame {
+      AMQPFrame::Method(channel_id, AMQPClass::Connection(method)) => {
+        let method = match method {
+          connection::AMQPMethod::StartOk(start_ok) => connection::AMQPMethod::StartOk(connection::StartOk { response: REDACTED.into(), ..start_ok.clone() }),
+          connection::AMQPMethod::SecureOk(_)       => connection::AMQPMethod::SecureOk(connection::SecureOk { response: REDACTED.into() }),
+          _                                         => return fmt::Debug::fmt(self.frame, f),
+        };
+        fmt::Debug::fmt(&AMQPFrame::Method(*channel_id, AMQPClass::Connection(method)), f)
+      },
+      AMQPFrame::Body(channel_id, payload) if payload.len() > self.max_body_size => {
+        f.debug_tuple("Body").field(channel_id).field(&TruncatedBody(&payload[..self.max_body_size], payload.len())).finish()
+      },
+      frame => fmt::Debug::fmt(frame, f),
+    }
+  }
+}
+
+struct TruncatedBody<'a>(&'a [u8], usize);
+
+impl fmt::Debug for TruncatedBody<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}... ({} bytes)", self.0, self.1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::types::FieldTable;
+
+  #[test]
+  fn redact_start_ok_response() {
+    let frame = AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::StartOk(connection::StartOk {
+      client_properties: FieldTable::default(),
+      mechanism:         "PLAIN".into(),
+      response:          "\0guest\0secret-password".into(),
+      locale:            "en_US".into(),
+    })));
+    let log = format!("{:?}", RedactedFrame::new(&frame, 16));
+    assert!(!log.contains("secret-password"));
+    assert!(log.contains(REDACTED));
+  }
+
+  #[test]
+  fn truncate_body() {
+    let frame = AMQPFrame::Body(1, b"0123456789".to_vec());
+    assert_eq!(format!("{:?}", RedactedFrame::new(&frame, 4)), "Body(1, [48, 49, 50, 51]... (10 bytes))");
+    assert_eq!(format!("{:?}", RedactedFrame::new(&frame, 16)), format!("{:?}", frame));
+  }
+}
@@ -1,23 +1,44 @@
-use log::error;
+use log::{trace, warn};
 use parking_lot::Mutex;
 
 use std::{
   collections::VecDeque,
+  fmt,
   sync::Arc,
 };
 
 use crate::{
   BasicProperties,
   message::BasicReturnMessage,
-  wait::WaitHandle,
 };
 
+/// How many returned messages we keep for `wait_for_confirms` when there is no delegate
+const MAX_BUFFERED_MESSAGES: usize = 1024;
+
+pub trait ReturnedMessageDelegate: Send + Sync {
+  fn on_returned_message(&self, message: BasicReturnMessage);
+}
+
+impl<F: Fn(BasicReturnMessage) + Send + Sync> ReturnedMessageDelegate for F {
+  fn on_returned_message(&self, message: BasicReturnMessage) {
+    self(message);
+  }
+}
+
 #[derive(Clone, Debug, Default)]
 pub(crate) struct ReturnedMessages {
   inner: Arc<Mutex<Inner>>,
 }
 
 impl ReturnedMessages {
+  pub(crate) fn set_delegate(&self, delegate: Box<dyn ReturnedMessageDelegate>) {
+    let mut inner = self.inner.lock();
+    for message in inner.messages.drain(..) {
+      delegate.on_returned_message(message);
+    }
+    inner.delegate = Some(delegate);
+  }
+
   pub(crate) fn start_new_delivery(&self, message: BasicReturnMessage) {
     self.inner.lock().current_message = Some(message);
   }
@@ -28,13 +49,13 @@ impl ReturnedMessages {
     }
   }
 
-  pub(crate) fn new_delivery_complete(&self) {
-    self.inner.lock().new_delivery_complete();
+  pub(crate) fn new_delivery_complete(&self) -> Option<BasicReturnMessage> {
+    self.inner.lock().new_delivery_complete()
   }
 
-  pub(crate) fn receive_delivery_content(&self, data: Vec<u8>) {
+  pub(crate) fn receive_delivery_content(&self, data: Vec<u8>, remaining_size: usize) {
     if let Some(message) = self.inner.lock().current_message.as_mut() {
-      message.delivery.data.extend(data);
+      message.delivery.receive_content(data, remaining_size);
     }
   }
 
@@ -42,26 +63,80 @@ impl ReturnedMessages {
     self.inner.lock().messages.drain(..).collect()
   }
 
-  pub(crate) fn register_waiter(&self, waiter: WaitHandle<()>) {
-    self.inner.lock().waiters.push_back(waiter);
+  pub(crate) fn buffered(&self) -> usize {
+    self.inner.lock().messages.len()
   }
 }
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct Inner {
   current_message: Option<BasicReturnMessage>,
-  messages:        Vec<BasicReturnMessage>,
-  waiters:         VecDeque<WaitHandle<()>>,
+  messages:        VecDeque<BasicReturnMessage>,
+  delegate:        Option<Box<dyn ReturnedMessageDelegate>>,
+}
+
+impl fmt::Debug for Inner {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Inner")
+      .field("current_message", &self.current_message)
+      .field("messages", &self.messages)
+      .field("has_delegate", &self.delegate.is_some())
+      .finish()
+  }
 }
 
 impl Inner {
-  fn new_delivery_complete(&mut self) {
-    if let Some(message) = self.current_message.take() {
-      error!("Server returned us a message: {:?}", message);
-      self.messages.push(message);
-      if let Some(wait_handle) = self.waiters.pop_front() {
-        wait_handle.finish(());
+  fn new_delivery_complete(&mut self) -> Option<BasicReturnMessage> {
+    let message = self.current_message.take()?;
+    trace!("Server returned us a message: {:?}", message);
+    if let Some(delegate) = self.delegate.as_ref() {
+      delegate.on_returned_message(message.clone());
+    } else {
+      if self.messages.len() == MAX_BUFFERED_MESSAGES {
+        if let Some(dropped) = self.messages.pop_front() {
+          warn!("Too many returned messages are buffered, dropping {:?}", dropped);
+        }
       }
+      self.messages.push_back(message.clone());
     }
+    Some(message)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::mpsc;
+
+  use crate::types::ShortString;
+
+  fn return_message(returned_messages: &ReturnedMessages, routing_key: &str) {
+    returned_messages.start_new_delivery(BasicReturnMessage::new("".into(), routing_key.into(), 312, "NO_ROUTE".into()));
+    returned_messages.new_delivery_complete();
+  }
+
+  #[test]
+  fn buffered_messages_are_bounded() {
+    let returned_messages = ReturnedMessages::default();
+    for i in 0..MAX_BUFFERED_MESSAGES + 10 {
+      return_message(&returned_messages, &i.to_string());
+    }
+    assert_eq!(returned_messages.buffered(), MAX_BUFFERED_MESSAGES);
+    assert_eq!(returned_messages.drain()[0].delivery.routing_key.as_str(), "10");
+  }
+
+  #[test]
+  fn delegate_receives_messages() {
+    let returned_messages  = ReturnedMessages::default();
+    let (sender, receiver) = mpsc::channel();
+    let sender             = Mutex::new(sender);
+    return_message(&returned_messages, "before");
+    returned_messages.set_delegate(Box::new(move |message: BasicReturnMessage| {
+      sender.lock().send(message.delivery.routing_key).unwrap();
+    }));
+    return_message(&returned_messages, "after");
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![ShortString::from("before"), ShortString::from("after")]);
+    assert_eq!(returned_messages.buffered(), 0);
   }
 }
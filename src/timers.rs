@@ -0,0 +1,73 @@
+use parking_lot::Mutex;
+
+use std::{
+  fmt,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+type Timer = Box<dyn FnOnce() + Send + 'static>;
+
+/// Callbacks run by the io loop once their deadline is reached, so that nothing has to sleep on a thread
+#[derive(Clone, Default)]
+pub(crate) struct Timers {
+  inner: Arc<Mutex<Vec<(Instant, Timer)>>>,
+}
+
+impl Timers {
+  pub(crate) fn schedule(&self, delay: Duration, timer: Timer) {
+    self.inner.lock().push((Instant::now() + delay, timer));
+  }
+
+  /// How long the io loop can sleep before running the next timer
+  pub(crate) fn next_timeout(&self) -> Option<Duration> {
+    let now = Instant::now();
+    self.inner.lock().iter().map(|(deadline, _)| *deadline).min().map(|deadline| if deadline > now { deadline - now } else { Duration::from_millis(0) })
+  }
+
+  /// Run the timers whose deadline is reached, or all of them if `all` is set
+  pub(crate) fn run(&self, all: bool) {
+    let now = Instant::now();
+    let due = {
+      let mut inner = self.inner.lock();
+      let (due, pending): (Vec<_>, Vec<_>) = inner.drain(..).partition(|(deadline, _)| all || *deadline <= now);
+      *inner = pending;
+      due
+    };
+    // Don't hold the lock while running them, as they can schedule new ones
+    for (_, timer) in due {
+      timer();
+    }
+  }
+}
+
+impl fmt::Debug for Timers {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Timers({})", self.inner.lock().len())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn timers_run_once_due() {
+    let timers = Timers::default();
+    let ran    = Arc::new(AtomicUsize::new(0));
+    assert_eq!(timers.next_timeout(), None);
+    for delay in &[0, 3600] {
+      let ran = ran.clone();
+      timers.schedule(Duration::from_secs(*delay), Box::new(move || { ran.fetch_add(1, Ordering::SeqCst); }));
+    }
+    assert_eq!(timers.next_timeout(), Some(Duration::from_millis(0)));
+    timers.run(false);
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+    assert!(timers.next_timeout().unwrap() > Duration::from_secs(3000));
+    timers.run(true);
+    assert_eq!(ran.load(Ordering::SeqCst), 2);
+    assert_eq!(timers.next_timeout(), None);
+  }
+}
@@ -0,0 +1,217 @@
+use log::error;
+
+use crate::{
+  Channel,
+  confirmation::Confirmation,
+};
+
+/// Rolls the transaction back unless it got committed or rolled back explicitly,
+/// e.g. when the code running in the transaction panics or its future gets dropped
+pub(crate) struct TransactionGuard {
+  channel:  Channel,
+  finished: bool,
+}
+
+impl TransactionGuard {
+  pub(crate) fn new(channel: Channel) -> Self {
+    Self { channel, finished: false }
+  }
+
+  pub(crate) fn commit(mut self) -> Confirmation<()> {
+    self.finished = true;
+    self.channel.tx_commit()
+  }
+
+  pub(crate) fn rollback(mut self) -> Confirmation<()> {
+    self.finished = true;
+    self.channel.tx_rollback()
+  }
+}
+
+impl Drop for TransactionGuard {
+  fn drop(&mut self) {
+    if !self.finished {
+      // We can't wait for the reply here, only log if it fails
+      let id = self.channel.id();
+      self.channel.tx_rollback().then(move |res| if let Err(err) = res {
+        error!("Failed to roll back the transaction on channel {}: {}", id, err);
+      });
+    }
+  }
+}
+
+#[cfg(feature = "futures")]
+pub(crate) mod futures {
+  use super::*;
+
+  use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+  };
+
+  use crate::error::Error;
+
+  /// The steps of `Channel::transaction_async`
+  pub(crate) struct TransactionFuture<F, Fut, T> {
+    channel: Channel,
+    f:       Option<F>,
+    state:   State<Fut, T>,
+  }
+
+  enum State<Fut, T> {
+    Selecting(Confirmation<()>),
+    Running(Pin<Box<Fut>>, TransactionGuard),
+    Committing(Confirmation<()>, Option<T>),
+    RollingBack(Confirmation<()>, Option<Error>),
+    Done,
+  }
+
+  // The future of the transaction is pinned in its own box, nothing else needs to be pinned
+  impl<F, Fut, T> Unpin for TransactionFuture<F, Fut, T> {}
+
+  impl<F, Fut, T> TransactionFuture<F, Fut, T> {
+    pub(crate) fn new(channel: Channel, tx_select: Confirmation<()>, f: F) -> Self {
+      Self { channel, f: Some(f), state: State::Selecting(tx_select) }
+    }
+  }
+
+  impl<F: FnOnce(Channel) -> Fut, Fut: Future<Output = Result<T, Error>>, T> Future for TransactionFuture<F, Fut, T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+      let this = self.get_mut();
+      loop {
+        this.state = match &mut this.state {
+          State::Selecting(tx_select)            => match Pin::new(tx_select).poll(cx) {
+            Poll::Pending         => return Poll::Pending,
+            Poll::Ready(Err(err)) => {
+              this.state = State::Done;
+              return Poll::Ready(Err(err));
+            },
+            Poll::Ready(Ok(()))   => {
+              let f = this.f.take().expect("transaction polled after completion");
+              State::Running(Box::pin(f(this.channel.clone())), TransactionGuard::new(this.channel.clone()))
+            },
+          },
+          State::Running(future, _)              => match future.as_mut().poll(cx) {
+            Poll::Pending    => return Poll::Pending,
+            Poll::Ready(res) => match mem::replace(&mut this.state, State::Done) {
+              State::Running(_, guard) => match res {
+                Ok(value) => State::Committing(guard.commit(), Some(value)),
+                Err(err)  => State::RollingBack(guard.rollback(), Some(err)),
+              },
+              _                        => unreachable!(),
+            },
+          },
+          State::Committing(tx_commit, value)    => match Pin::new(tx_commit).poll(cx) {
+            Poll::Pending         => return Poll::Pending,
+            Poll::Ready(Err(err)) => {
+              this.state = State::Done;
+              return Poll::Ready(Err(err));
+            },
+            Poll::Ready(Ok(()))   => {
+              let value = value.take().expect("transaction polled after completion");
+              this.state = State::Done;
+              return Poll::Ready(Ok(value));
+            },
+          },
+          State::RollingBack(tx_rollback, error) => match Pin::new(tx_rollback).poll(cx) {
+            Poll::Pending    => return Poll::Pending,
+            Poll::Ready(res) => {
+              if let Err(err) = res {
+                error!("Failed to roll back the transaction on channel {}: {}", this.channel.id(), err);
+              }
+              let error = error.take().expect("transaction polled after completion");
+              this.state = State::Done;
+              return Poll::Ready(Err(error));
+            },
+          },
+          State::Done                            => panic!("transaction polled after completion"),
+        };
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use amq_protocol::{
+    frame::AMQPFrame,
+    protocol::{AMQPClass, channel, tx},
+  };
+
+  use std::{
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use crate::{
+    Connection,
+    connection_status::ConnectionState,
+    error::{Error, ErrorKind},
+  };
+
+  fn open_channel(conn: &Connection) -> Channel {
+    conn.set_state(ConnectionState::Connected);
+    conn.configuration().set_channel_max(2047);
+    let channel = conn.create_channel();
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk::default())))).unwrap();
+    channel.try_wait().unwrap().unwrap()
+  }
+
+  /// Answer the request of the transaction running on another thread, once it's sent
+  fn reply(conn: &Connection, expected: &str, method: tx::AMQPMethod) {
+    let start = Instant::now();
+    while conn.expected_replies(1) != vec![expected] {
+      assert!(start.elapsed() < Duration::from_secs(5), "{} never got expected", expected);
+      thread::sleep(Duration::from_millis(1));
+    }
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Tx(method))).unwrap();
+  }
+
+  #[test]
+  fn transaction_is_committed_when_it_succeeds() {
+    let _ = env_logger::try_init();
+
+    let conn        = Connection::default();
+    let channel     = open_channel(&conn);
+    let transaction = thread::spawn(move || channel.transaction(|_| Ok(42)));
+    reply(&conn, "tx.select-ok", tx::AMQPMethod::SelectOk(tx::SelectOk {}));
+    reply(&conn, "tx.commit-ok", tx::AMQPMethod::CommitOk(tx::CommitOk {}));
+    assert_eq!(transaction.join().unwrap().unwrap(), 42);
+  }
+
+  #[test]
+  fn transaction_is_rolled_back_when_it_fails() {
+    let _ = env_logger::try_init();
+
+    let conn        = Connection::default();
+    let channel     = open_channel(&conn);
+    channel.status().set_transaction();
+    let transaction = thread::spawn(move || channel.transaction(|_| -> Result<(), Error> { Err(ErrorKind::NotConnected.into()) }));
+    reply(&conn, "tx.rollback-ok", tx::AMQPMethod::RollbackOk(tx::RollbackOk {}));
+    match transaction.join().unwrap() {
+      Err(err) => match err.kind() {
+        ErrorKind::NotConnected => {},
+        kind                    => panic!("unexpected error: {:?}", kind),
+      },
+      Ok(())   => panic!("the transaction should have failed"),
+    }
+  }
+
+  #[test]
+  fn transaction_is_rolled_back_when_dropped() {
+    let _ = env_logger::try_init();
+
+    let conn    = Connection::default();
+    let channel = open_channel(&conn);
+    drop(TransactionGuard::new(channel.clone()));
+    assert_eq!(conn.expected_replies(1), vec!["tx.rollback-ok"]);
+    TransactionGuard::new(channel).commit();
+    assert_eq!(conn.expected_replies(1), vec!["tx.rollback-ok", "tx.commit-ok"]);
+  }
+}
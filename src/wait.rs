@@ -1,78 +1,139 @@
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
 use std::{
   fmt,
-  sync::{
-    Arc,
-    mpsc::{SyncSender, Receiver, sync_channel},
-  },
+  sync::Arc,
+  time::{Duration, Instant},
 };
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 
+/// A oneshot completion, sharing a single allocation with its `WaitHandle`s
 pub struct Wait<T> {
-  recv: Receiver<Result<T, Error>>,
-  send: SyncSender<Result<T, Error>>,
-  task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+  inner: Arc<Inner<T>>,
 }
 
-#[derive(Clone)]
 pub struct WaitHandle<T> {
-  send: SyncSender<Result<T, Error>>,
-  task: Arc<Mutex<Option<Box<dyn NotifyReady + Send>>>>,
+  inner: Arc<Inner<T>>,
 }
 
 pub trait NotifyReady {
   fn notify(&self);
 }
 
+struct Inner<T> {
+  state:   Mutex<State<T>>,
+  condvar: Condvar,
+}
+
+struct State<T> {
+  value:    Option<Result<T, Error>>,
+  finished: bool,
+  task:     Option<Box<dyn NotifyReady + Send>>,
+}
+
 impl<T> Wait<T> {
-  pub(crate) fn new() -> (Self, WaitHandle<T>) {
-    let (send, recv) = sync_channel(1);
-    let wait         = Self { recv, send, task: Arc::new(Mutex::new(None)) };
-    let wait_handle  = wait.handle();
-    (wait, wait_handle)
+  pub fn new() -> (Self, WaitHandle<T>) {
+    let inner       = Arc::new(Inner {
+      state:   Mutex::new(State { value: None, finished: false, task: None }),
+      condvar: Condvar::new(),
+    });
+    let wait_handle = WaitHandle { inner: inner.clone() };
+    (Self { inner }, wait_handle)
   }
 
-  fn handle(&self) -> WaitHandle<T> {
-    WaitHandle { send: self.send.clone(), task: self.task.clone() }
+  pub fn try_wait(&self) -> Option<Result<T, Error>> {
+    self.inner.state.lock().value.take()
   }
 
-  pub(crate) fn try_wait(&self) -> Option<Result<T, Error>> {
-    self.recv.try_recv().ok()
+  /// Block until the value is there, fails if it was already taken
+  pub fn wait(&self) -> Result<T, Error> {
+    let mut state = self.inner.state.lock();
+    loop {
+      if let Some(value) = state.take() {
+        return value;
+      }
+      self.inner.condvar.wait(&mut state);
+    }
   }
 
-  pub(crate) fn wait(&self) -> Result<T, Error> {
-    self.recv.recv().unwrap()
+  /// Wait at most `timeout`, returns None if it elapsed
+  pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T, Error>> {
+    let deadline  = Instant::now() + timeout;
+    let mut state = self.inner.state.lock();
+    loop {
+      if let Some(value) = state.take() {
+        return Some(value);
+      }
+      if self.inner.condvar.wait_until(&mut state, deadline).timed_out() {
+        return state.take();
+      }
+    }
   }
 
   pub(crate) fn subscribe(&self, task: Box<dyn NotifyReady + Send>) {
-    *self.task.lock() = Some(task);
+    self.inner.state.lock().task = Some(task);
   }
 
   pub(crate) fn has_subscriber(&self) -> bool {
-    self.task.lock().is_some()
+    self.inner.state.lock().task.is_some()
+  }
+
+  /// Subscribe `task` unless the value is already there, returns whether it got subscribed
+  pub(crate) fn subscribe_unless_ready(&self, task: Box<dyn NotifyReady + Send>) -> bool {
+    let mut state = self.inner.state.lock();
+    if state.finished {
+      false
+    } else {
+      state.task = Some(task);
+      true
+    }
+  }
+}
+
+impl<T> State<T> {
+  /// The value if it's there, an error if it was already taken
+  fn take(&mut self) -> Option<Result<T, Error>> {
+    match self.value.take() {
+      None if self.finished => Some(Err(ErrorKind::AlreadyConsumed.into())),
+      value                 => value,
+    }
   }
 }
 
 impl<T> WaitHandle<T> {
-  pub(crate) fn finish(&self, val: T) {
-    let _ = self.send.send(Ok(val));
-    self.notify();
+  pub fn finish(&self, val: T) {
+    self.complete(Ok(val));
   }
 
-  pub(crate) fn error(&self, error: Error) {
-    let _ = self.send.send(Err(error));
-    self.notify();
+  pub fn error(&self, error: Error) {
+    self.complete(Err(error));
   }
 
-  fn notify(&self) {
-    if let Some(task) = self.task.lock().take() {
+  /// Only the first completion is kept, the following ones are ignored
+  fn complete(&self, value: Result<T, Error>) {
+    let task = {
+      let mut state = self.inner.state.lock();
+      if state.finished {
+        return;
+      }
+      state.finished = true;
+      state.value    = Some(value);
+      state.task.take()
+    };
+    self.inner.condvar.notify_all();
+    if let Some(task) = task {
       task.notify();
     }
   }
 }
 
+impl<T> Clone for WaitHandle<T> {
+  fn clone(&self) -> Self {
+    Self { inner: self.inner.clone() }
+  }
+}
+
 impl<T> fmt::Debug for Wait<T> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "Wait")
@@ -84,3 +145,66 @@ impl<T> fmt::Debug for WaitHandle<T> {
     write!(f, "WaitHandle")
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::{AtomicBool, Ordering};
+
+  use crate::error::ErrorKind;
+
+  struct Task(Arc<AtomicBool>);
+
+  impl NotifyReady for Task {
+    fn notify(&self) {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
+
+  #[test]
+  fn first_completion_wins() {
+    let (wait, wait_handle) = Wait::new();
+    wait_handle.finish(1);
+    wait_handle.clone().finish(2);
+    wait_handle.error(ErrorKind::NotConnected.into());
+    assert_eq!(wait.try_wait().unwrap().unwrap(), 1);
+    assert!(wait.try_wait().is_none());
+  }
+
+  #[test]
+  fn wait_timeout_expires() {
+    let (wait, wait_handle) = Wait::<()>::new();
+    assert!(wait.wait_timeout(Duration::from_millis(10)).is_none());
+    wait_handle.finish(());
+    assert!(wait.wait_timeout(Duration::from_millis(10)).unwrap().is_ok());
+  }
+
+  #[test]
+  fn waiting_for_a_taken_value_fails() {
+    let (wait, wait_handle) = Wait::new();
+    wait_handle.finish(1);
+    assert_eq!(wait.try_wait().unwrap().unwrap(), 1);
+    assert!(match wait.wait() {
+      Err(err) => match err.kind() {
+        ErrorKind::AlreadyConsumed => true,
+        _                          => false,
+      },
+      Ok(_)    => false,
+    });
+    assert!(wait.wait_timeout(Duration::from_millis(10)).unwrap().is_err());
+  }
+
+  #[test]
+  fn subscriber_is_notified() {
+    let (wait, wait_handle) = Wait::new();
+    let notified            = Arc::new(AtomicBool::new(false));
+    wait.subscribe(Box::new(Task(notified.clone())));
+    assert!(wait.has_subscriber());
+    assert!(!notified.load(Ordering::SeqCst));
+    wait_handle.finish(42);
+    assert!(notified.load(Ordering::SeqCst));
+    assert!(!wait.has_subscriber());
+    assert_eq!(wait.wait().unwrap(), 42);
+  }
+}
@@ -37,6 +37,37 @@ pub(crate) enum Reply {
   {{/each ~}}
 }
 
+impl Reply {
+  pub(crate) fn name(&self) -> &'static str {
+    match self {
+      {{#each protocol.classes as |class| ~}}
+      {{#each class.methods as |method| ~}}
+      {{#if method.c2s ~}}
+      {{#if method.synchronous ~}}
+      Reply::Awaiting{{camel class.name}}{{camel method.name}}Ok(..) => "{{class.name}}.{{method.name}}-ok",
+      {{/if ~}}
+      {{/if ~}}
+      {{/each ~}}
+      {{/each ~}}
+    }
+  }
+
+  /// We won't get the reply, let whoever is waiting for it know why
+  pub(crate) fn error(self, error: Error) {
+    match self {
+      {{#each protocol.classes as |class| ~}}
+      {{#each class.methods as |method| ~}}
+      {{#if method.c2s ~}}
+      {{#if method.synchronous ~}}
+      Reply::Awaiting{{camel class.name}}{{camel method.name}}Ok(wait_handle, ..) => wait_handle.error(error),
+      {{/if ~}}
+      {{/if ~}}
+      {{/each ~}}
+      {{/each ~}}
+    }
+  }
+}
+
 impl Channel {
   pub(crate) fn receive_method(&self, method: AMQPClass) -> Result<(), Error> {
     match method {
@@ -155,24 +186,29 @@ impl Channel {
       return Err(ErrorKind::NotConnected.into());
     }
 
-    match self.connection.next_expected_reply(self.id) {
+    match self.connection.next_expected_reply(self.id()) {
       Some(Reply::Awaiting{{camel class.name}}{{camel method.name}}(wait_handle{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}})) => {
-        {{#unless method.metadata.confirmation.type ~}}
-        wait_handle.finish(Default::default());
-        {{/unless ~}}
+        {{#if method.metadata.confirmation.type ~}}
+        self.on_{{snake class.name false}}_{{snake method.name false}}_received(method, wait_handle{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}})
+        {{else}}
+        // Let the hook update our state before waking up whoever waits for this reply
         {{#if method.arguments ~}}
-        self.on_{{snake class.name false}}_{{snake method.name false}}_received(method{{#if method.metadata.confirmation.type ~}}, wait_handle{{/if ~}}{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}})
+        let res = self.on_{{snake class.name false}}_{{snake method.name false}}_received(method{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}});
         {{else}}
         {{#if method.metadata.received_hook ~}}
-        self.on_{{snake class.name false}}_{{snake method.name false}}_received({{#each method.metadata.received_hook.params as |param| ~}}{{#unless @first ~}}, {{/unless ~}}{{param}}{{/each ~}})
+        let res = self.on_{{snake class.name false}}_{{snake method.name false}}_received({{#each method.metadata.received_hook.params as |param| ~}}{{#unless @first ~}}, {{/unless ~}}{{param}}{{/each ~}});
         {{else}}
-        Ok(())
+        let res = Ok(());
         {{/if ~}}
         {{/if ~}}
+        wait_handle.finish(Default::default());
+        res
+        {{/if ~}}
       },
       _ => {
-        self.set_error()?;
-        Err(ErrorKind::UnexpectedReply.into())
+        let error: Error = ErrorKind::UnexpectedReply.into();
+        self.set_error(CloseReason::from(&error))?;
+        Err(error)
       },
     }
   }